@@ -0,0 +1,43 @@
+use chrono::{DateTime, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, TimeZone};
+
+use crate::schedule::gap_end;
+
+/// A date and time given on the command line, either with an explicit UTC
+/// offset or in the time zone croncycle schedules in.
+#[derive(Clone, Debug)]
+pub enum DateTimeArg {
+    Fixed(DateTime<FixedOffset>),
+    Local(NaiveDateTime),
+}
+
+impl DateTimeArg {
+    /// Parses RFC 3339 (`2024-05-01T09:00:00+02:00`), `2024-05-01 09:00[:00]`
+    /// or `2024-05-01`, the latter two in the schedule's time zone.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+            return Ok(DateTimeArg::Fixed(t));
+        }
+        for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"] {
+            if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+                return Ok(DateTimeArg::Local(t));
+            }
+        }
+        if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(DateTimeArg::Local(d.and_hms_opt(0, 0, 0).unwrap()));
+        }
+        Err("expected a date and time like 2024-05-01 09:00, 2024-05-01 or 2024-05-01T09:00:00+02:00".to_string())
+    }
+
+    /// Resolves the date and time in `tz`. Local times that occur twice resolve
+    /// to the first occurrence, those skipped by daylight saving time to the
+    /// end of the gap.
+    pub fn resolve<Z: TimeZone>(&self, tz: &Z) -> DateTime<Z> {
+        match self {
+            DateTimeArg::Fixed(t) => t.with_timezone(tz),
+            DateTimeArg::Local(t) => match tz.from_local_datetime(t) {
+                LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => t,
+                LocalResult::None => gap_end(tz, t),
+            },
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::{DateTime, Datelike, Days, Local, Months, NaiveDate, TimeDelta, TimeZone, Timelike};
+use cron::Schedule;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Fire times to leave out of a schedule, from --except and --except-dates
+#[derive(Default)]
+pub struct Exclusions {
+    /// Expressions, and whether they match whole minutes like crontab lines
+    crons: Vec<(String, Box<Schedule>, bool)>,
+    dates: Vec<(PathBuf, BTreeSet<NaiveDate>)>,
+}
+
+/// How many years from today recurring calendar events are expanded for
+const RECURRENCE_YEARS: u32 = 10;
+
+/// An event of an iCalendar file while it is being read
+#[derive(Default)]
+struct Event {
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    rrule: Option<String>,
+    exdates: BTreeSet<NaiveDate>,
+}
+
+/// How often a recurring event repeats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// The parts of an RRULE croncycle understands
+#[derive(Debug)]
+struct Recurrence {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<NaiveDate>,
+}
+
+impl Exclusions {
+    pub fn add_cron(&mut self, expr: String, schedule: Box<Schedule>, whole_minute: bool) {
+        self.crons.push((expr, schedule, whole_minute));
+    }
+
+    pub fn add_dates(&mut self, path: PathBuf, dates: BTreeSet<NaiveDate>) {
+        self.dates.push((path, dates));
+    }
+
+    /// Says why the fire time `t` is excluded, `None` if it is not.
+    pub fn reason<Z: TimeZone>(&self, t: &DateTime<Z>) -> Option<String> {
+        let minute = t.with_second(0).unwrap_or_else(|| t.clone());
+        for (expr, schedule, whole_minute) in &self.crons {
+            if schedule.includes(if *whole_minute { minute.clone() } else { t.clone() }) {
+                return Some(format!("it matches --except {}", expr));
+            }
+        }
+        let date = t.date_naive();
+        self.dates
+            .iter()
+            .find(|(_, dates)| dates.contains(&date))
+            .map(|(path, _)| format!("{} is listed in {}", date, path.display()))
+    }
+}
+
+/// Reads a list of dates, one `YYYY-MM-DD` per line with `#` comments, or an
+/// iCalendar file when it ends in `.ics` or starts like one.
+pub fn load_dates(path: &Path) -> Result<BTreeSet<NaiveDate>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let is_ics = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ics"))
+        || contents.trim_start().starts_with("BEGIN:VCALENDAR");
+    if is_ics {
+        let horizon = Local::now().date_naive() + Months::new(12 * RECURRENCE_YEARS);
+        parse_ics(&contents, horizon).map_err(|e| format!("{}: {}", path.display(), e))
+    } else {
+        parse_list(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+fn parse_list(contents: &str) -> Result<BTreeSet<NaiveDate>, String> {
+    let mut dates = BTreeSet::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        // Anything after the date, such as the name of the holiday, is ignored.
+        let Some(date) = line.split_whitespace().next() else {
+            continue;
+        };
+        match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+            Ok(date) => {
+                dates.insert(date);
+            },
+            Err(_) => return Err(format!("line {}: expected a date like 2024-12-25, found '{}'", n + 1, date)),
+        }
+    }
+    Ok(dates)
+}
+
+/// Collects the days covered by the events of an iCalendar file, repeating
+/// recurring events up to `horizon`. Times of day and time zones are ignored,
+/// an event counts for every date it touches.
+fn parse_ics(contents: &str, horizon: NaiveDate) -> Result<BTreeSet<NaiveDate>, String> {
+    // Long lines are folded by starting their continuation with whitespace.
+    let unfolded = contents.replace("\r\n", "\n").replace("\n ", "").replace("\n\t", "");
+
+    let mut dates = BTreeSet::new();
+    let mut event: Option<Event> = None;
+    for line in unfolded.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.split(';').next().unwrap_or_default().to_ascii_uppercase();
+        match (name.as_str(), value.trim()) {
+            ("BEGIN", "VEVENT") => event = Some(Event::default()),
+            ("DTSTART", value) => {
+                if let Some(event) = &mut event {
+                    event.start = Some(ics_date(value).ok_or_else(|| format!("invalid DTSTART '{}'", value))?);
+                }
+            },
+            ("DTEND", value) => {
+                if let Some(event) = &mut event {
+                    let end = ics_date(value).ok_or_else(|| format!("invalid DTEND '{}'", value))?;
+                    // All-day events and those ending at midnight end before DTEND's date.
+                    let exclusive = value.len() == 8 || value[8..].trim_start_matches('T').starts_with("000000");
+                    event.end = Some(if exclusive { end - TimeDelta::days(1) } else { end });
+                }
+            },
+            ("RRULE", value) => {
+                if let Some(event) = &mut event {
+                    event.rrule = Some(value.to_string());
+                }
+            },
+            ("EXDATE", value) => {
+                if let Some(event) = &mut event {
+                    for exdate in value.split(',') {
+                        event.exdates.insert(ics_date(exdate).ok_or_else(|| format!("invalid EXDATE '{}'", exdate))?);
+                    }
+                }
+            },
+            ("END", "VEVENT") => {
+                let Some(Event { start: Some(start), end, rrule, exdates }) = event.take() else {
+                    return Err("event without DTSTART".to_string());
+                };
+                let days = (end.unwrap_or(start) - start).num_days().max(0);
+                let starts = match rrule {
+                    Some(rrule) => {
+                        let recurrence = parse_rrule(&rrule, start).map_err(|e| format!("RRULE '{}': {}", rrule, e))?;
+                        recurrence.starts(start, horizon)
+                    },
+                    None => vec![start],
+                };
+                for start in starts.into_iter().filter(|start| !exdates.contains(start)) {
+                    dates.extend(start.iter_days().take(days as usize + 1));
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(dates)
+}
+
+/// Parses an RRULE repeating an event starting on `start`. Rules picking days
+/// other than those of `start`, such as `BYDAY=4TH`, are rejected.
+fn parse_rrule(rrule: &str, start: NaiveDate) -> Result<Recurrence, String> {
+    let mut recurrence = Recurrence { frequency: Frequency::Daily, interval: 1, count: None, until: None };
+    let mut frequency = None;
+    for part in rrule.split(';') {
+        let (name, value) = part.split_once('=').ok_or_else(|| format!("invalid part '{}'", part))?;
+        let number = || value.parse::<u32>().ok().filter(|n| *n > 0).ok_or_else(|| format!("invalid {} '{}'", name, value));
+        match name.to_ascii_uppercase().as_str() {
+            "FREQ" => {
+                frequency = Some(match value.to_ascii_uppercase().as_str() {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    "MONTHLY" => Frequency::Monthly,
+                    "YEARLY" => Frequency::Yearly,
+                    _ => return Err(format!("FREQ={} is not supported, use DAILY, WEEKLY, MONTHLY or YEARLY", value)),
+                })
+            },
+            "INTERVAL" => recurrence.interval = number()?,
+            "COUNT" => recurrence.count = Some(number()?),
+            "UNTIL" => recurrence.until = Some(ics_date(value).ok_or_else(|| format!("invalid UNTIL '{}'", value))?),
+            "WKST" => {},
+            // Exporters like to spell out what DTSTART already says.
+            "BYMONTH" if value.parse() == Ok(start.month()) => {},
+            "BYMONTHDAY" if value.parse() == Ok(start.day()) => {},
+            "BYDAY" if value.eq_ignore_ascii_case(&start.weekday().to_string()[..2]) => {},
+            _ => return Err(format!("{} is not supported, list the dates of the event instead", part)),
+        }
+    }
+    recurrence.frequency = frequency.ok_or("FREQ is missing")?;
+    Ok(recurrence)
+}
+
+impl Recurrence {
+    /// Start dates of the occurrences of an event starting on `start`, up to `horizon`.
+    fn starts(&self, start: NaiveDate, horizon: NaiveDate) -> Vec<NaiveDate> {
+        let last = self.until.map_or(horizon, |until| until.min(horizon));
+        let mut starts = Vec::new();
+        for n in 0u32.. {
+            let Some(step) = n.checked_mul(self.interval) else {
+                break;
+            };
+            // The first day of the period the occurrence falls in, and the
+            // occurrence itself unless the month lacks the day of `start`.
+            let (period, date) = match self.frequency {
+                Frequency::Daily | Frequency::Weekly => {
+                    let days = if self.frequency == Frequency::Weekly { 7 * step as u64 } else { step as u64 };
+                    let date = start.checked_add_days(Days::new(days));
+                    (date, date)
+                },
+                Frequency::Monthly | Frequency::Yearly => {
+                    let months = if self.frequency == Frequency::Yearly { step.saturating_mul(12) } else { step };
+                    (months_later(start, months, 1), months_later(start, months, start.day()))
+                },
+            };
+            if period.is_none_or(|period| period > last) || self.count.is_some_and(|count| starts.len() >= count as usize) {
+                break;
+            }
+            starts.extend(date.filter(|date| *date <= last));
+        }
+        starts
+    }
+}
+
+/// Day `day` of the month `months` months after the month of `start`, if it has that day.
+fn months_later(start: NaiveDate, months: u32, day: u32) -> Option<NaiveDate> {
+    let month0 = start.month0().checked_add(months)?;
+    NaiveDate::from_ymd_opt(start.year().checked_add((month0 / 12) as i32)?, month0 % 12 + 1, day)
+}
+
+/// Parses the date of an iCalendar DATE (`20241225`) or DATE-TIME (`20241225T090000Z`).
+fn ics_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.get(..8)?, "%Y%m%d").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn parses_date_list() {
+        let dates = parse_list("# Company holidays\n2024-12-25 Christmas\n\n2024-12-26\n").unwrap();
+        assert_eq!(dates, BTreeSet::from([date("2024-12-25"), date("2024-12-26")]));
+        assert_eq!(parse_list("2024-12-25\n25.12.2024\n").err().unwrap(), "line 2: expected a date like 2024-12-25, found '25.12.2024'");
+    }
+
+    #[test]
+    fn parses_ics() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\nSUMMARY:Christmas\r\nDTSTART;VALUE=DATE:20241225\r\nDTEND;VALUE=DATE:20241227\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nDTSTART:20250301T090000Z\r\nDTEND:20250301T170000Z\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        assert_eq!(
+            parse_ics(ics, date("2030-01-01")).unwrap(),
+            BTreeSet::from([date("2024-12-25"), date("2024-12-26"), date("2025-03-01")]),
+        );
+    }
+
+    #[test]
+    fn expands_recurring_events() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\nSUMMARY:New Year\r\nDTSTART;VALUE=DATE:20240101\r\nRRULE:FREQ=YEARLY;BYMONTH=1;BYMONTHDAY=1\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nSUMMARY:Retreat\r\nDTSTART;VALUE=DATE:20240610\r\nDTEND;VALUE=DATE:20240612\r\n\
+            RRULE:FREQ=YEARLY;INTERVAL=2;UNTIL=20280101\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nSUMMARY:Inventory\r\nDTSTART;VALUE=DATE:20240131\r\nRRULE:FREQ=MONTHLY;COUNT=3\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nSUMMARY:Freeze\r\nDTSTART;VALUE=DATE:20240701\r\nRRULE:FREQ=DAILY;COUNT=3\r\nEXDATE;VALUE=DATE:20240702\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        assert_eq!(
+            parse_ics(ics, date("2026-06-30")).unwrap(),
+            BTreeSet::from([
+                date("2024-01-01"), date("2025-01-01"), date("2026-01-01"),
+                date("2024-06-10"), date("2024-06-11"), date("2026-06-10"), date("2026-06-11"),
+                // February and April have no day 31.
+                date("2024-01-31"), date("2024-03-31"), date("2024-05-31"),
+                date("2024-07-01"), date("2024-07-03"),
+            ]),
+        );
+    }
+
+    #[test]
+    fn expands_leap_days_and_weeks() {
+        let leap_day = Recurrence { frequency: Frequency::Yearly, interval: 1, count: None, until: None };
+        assert_eq!(leap_day.starts(date("2024-02-29"), date("2032-12-31")), [date("2024-02-29"), date("2028-02-29"), date("2032-02-29")]);
+
+        let weekly = parse_rrule("FREQ=WEEKLY;BYDAY=FR;COUNT=2", date("2024-05-03")).unwrap();
+        assert_eq!(weekly.starts(date("2024-05-03"), date("2030-01-01")), [date("2024-05-03"), date("2024-05-10")]);
+    }
+
+    #[test]
+    fn rejects_unsupported_rules() {
+        let start = date("2024-11-28");
+        assert_eq!(
+            parse_rrule("FREQ=YEARLY;BYMONTH=11;BYDAY=4TH", start).err().unwrap(),
+            "BYDAY=4TH is not supported, list the dates of the event instead",
+        );
+        assert!(parse_rrule("FREQ=HOURLY", start).is_err());
+        assert!(parse_rrule("COUNT=3", start).is_err());
+    }
+}
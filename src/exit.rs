@@ -0,0 +1,154 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::process::ExitStatus;
+
+/// How a failed run ended: with an exit code or killed by a signal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    Code(i32),
+    Signal(i32),
+}
+
+/// Exit codes or a signal given on the command line, such as `3`, `1-5` or `SIGTERM`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CodePattern {
+    Codes(RangeInclusive<i32>),
+    Signal(i32),
+}
+
+#[cfg(unix)]
+const SIGNALS: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+#[cfg(not(unix))]
+const SIGNALS: &[(&str, i32)] = &[];
+
+impl ExitCode {
+    pub fn of(status: &ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return ExitCode::Signal(signal);
+            }
+        }
+        ExitCode::Code(status.code().unwrap_or_default())
+    }
+
+    /// Parses an exit code (`3`) or a signal name (`SIGTERM`, `term`).
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if let Ok(code) = s.parse() {
+            return Ok(ExitCode::Code(code));
+        }
+        let name = s.to_ascii_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS
+            .iter()
+            .find(|(signal, _)| *signal == name)
+            .map(|(_, signo)| ExitCode::Signal(*signo))
+            .ok_or_else(|| format!("expected an exit code or a signal name like SIGTERM, found '{}'", s))
+    }
+
+    /// Status croncycle exits with for this, 128 plus the signal number for
+    /// signals like shells do.
+    pub fn status(&self) -> i32 {
+        match self {
+            ExitCode::Code(code) => *code,
+            ExitCode::Signal(signo) => 128 + signo,
+        }
+    }
+}
+
+impl CodePattern {
+    /// Parses an exit code, a range of exit codes or a signal name.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some((start, end)) = s.trim().split_once('-').filter(|(start, _)| !start.is_empty()) {
+            let parse = |code: &str| code.trim().parse::<i32>().map_err(|_| format!("invalid exit code range '{}'", s));
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                return Err(format!("exit code range '{}' is empty", s));
+            }
+            return Ok(CodePattern::Codes(start..=end));
+        }
+        Ok(match ExitCode::parse(s)? {
+            ExitCode::Code(code) => CodePattern::Codes(code..=code),
+            ExitCode::Signal(signo) => CodePattern::Signal(signo),
+        })
+    }
+
+    pub fn matches(&self, code: ExitCode) -> bool {
+        match (self, code) {
+            (CodePattern::Codes(codes), ExitCode::Code(code)) => codes.contains(&code),
+            (CodePattern::Signal(signo), ExitCode::Signal(signal)) => *signo == signal,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitCode::Code(code) => write!(f, "exit code {}", code),
+            ExitCode::Signal(signo) => match SIGNALS.iter().find(|(_, s)| s == signo) {
+                Some((name, _)) => write!(f, "SIG{}", name),
+                None => write!(f, "signal {}", signo),
+            },
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_codes_and_signals() {
+        assert_eq!(ExitCode::parse("3"), Ok(ExitCode::Code(3)));
+        assert_eq!(ExitCode::parse("SIGTERM"), Ok(ExitCode::Signal(libc::SIGTERM)));
+        assert_eq!(ExitCode::parse("kill"), Ok(ExitCode::Signal(libc::SIGKILL)));
+        assert!(ExitCode::parse("SIGFOO").is_err());
+        assert_eq!(ExitCode::Signal(libc::SIGTERM).to_string(), "SIGTERM");
+        assert_eq!(ExitCode::Signal(libc::SIGTERM).status(), 143);
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let pattern = CodePattern::parse("1-5").unwrap();
+        assert!(pattern.matches(ExitCode::Code(1)) && pattern.matches(ExitCode::Code(5)));
+        assert!(!pattern.matches(ExitCode::Code(6)));
+        assert!(!pattern.matches(ExitCode::Signal(1)));
+        assert!(CodePattern::parse("SIGPIPE").unwrap().matches(ExitCode::Signal(libc::SIGPIPE)));
+        assert!(CodePattern::parse("5-1").is_err());
+        assert!(CodePattern::parse("1-x").is_err());
+    }
+}
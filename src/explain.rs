@@ -0,0 +1,400 @@
+use chrono::Utc;
+use clap::Args;
+use cron::{Schedule, TimeUnitSpec};
+use log::error;
+
+use crate::syntax::{self, Parsed, Syntax};
+use crate::INVALID_SCHEDULE_CODE;
+
+#[derive(Args)]
+pub struct ExplainArgs {
+    /// Cron expression to explain, or one of the @ macros
+    expression: String,
+
+    /// Syntax of the cron expression
+    #[arg(long = "syntax", value_enum, default_value_t = Syntax::Auto)]
+    syntax: Syntax,
+}
+
+const DAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+
+/// Longest length of each month, counting February in leap years
+const MONTH_DAYS: [u32; 12] = [31, 29, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+/// How the values a field matches are laid out
+#[derive(Debug, PartialEq, Eq)]
+enum Shape {
+    All,
+    Single(u32),
+    Range(u32, u32),
+    /// Every `step` from `start` to the end of the field
+    Step(u32, u32),
+    List(Vec<u32>),
+}
+
+impl Shape {
+    fn of(spec: &impl TimeUnitSpec, min: u32, max: u32) -> Self {
+        let values: Vec<u32> = spec.iter().collect();
+        let (first, last) = (values[0], values[values.len() - 1]);
+        if values.len() as u32 == max - min + 1 {
+            Shape::All
+        } else if values.len() == 1 {
+            Shape::Single(first)
+        } else if last - first + 1 == values.len() as u32 {
+            Shape::Range(first, last)
+        } else if values.len() > 2 && last + (values[1] - first) > max && values.windows(2).all(|w| w[1] - w[0] == values[1] - first) {
+            Shape::Step(first, values[1] - first)
+        } else {
+            Shape::List(values)
+        }
+    }
+
+    fn is_all(&self) -> bool {
+        *self == Shape::All
+    }
+}
+
+/// The shapes of all fields of a schedule
+struct Fields {
+    seconds: Shape,
+    minutes: Shape,
+    hours: Shape,
+    days_of_month: Shape,
+    months: Shape,
+    days_of_week: Shape,
+    years: Shape,
+}
+
+impl Fields {
+    fn of(schedule: &Schedule) -> Self {
+        Fields {
+            seconds: Shape::of(schedule.seconds(), 0, 59),
+            minutes: Shape::of(schedule.minutes(), 0, 59),
+            hours: Shape::of(schedule.hours(), 0, 23),
+            days_of_month: Shape::of(schedule.days_of_month(), 1, 31),
+            months: Shape::of(schedule.months(), 1, 12),
+            days_of_week: Shape::of(schedule.days_of_week(), 1, 7),
+            years: Shape::of(schedule.years(), 1970, 2100),
+        }
+    }
+}
+
+/// Prints a description of the expression in `args` and any surprises in it.
+pub fn print(args: &ExplainArgs) {
+    let schedule = match syntax::parse(&args.expression, args.syntax) {
+        Ok(Parsed::Cron(schedule)) => schedule,
+        Ok(Parsed::Either(by_month_day, by_week_day)) => {
+            println!("{}, or {}", describe(&by_month_day), describe(&by_week_day));
+            for warning in either_surprises(&by_month_day, &by_week_day, &args.expression, args.syntax) {
+                println!("Warning: {}", warning);
+            }
+            return;
+        },
+        Ok(Parsed::Reboot) => {
+            println!("once, when croncycle starts");
+            return;
+        },
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(INVALID_SCHEDULE_CODE);
+        },
+    };
+
+    println!("{}", describe(&schedule));
+    for warning in surprises(&schedule, &args.expression, args.syntax) {
+        println!("Warning: {}", warning);
+    }
+}
+
+/// Describes when a schedule fires, e.g. "at 09:30:00 on every weekday".
+pub fn describe(schedule: &Schedule) -> String {
+    let fields = Fields::of(schedule);
+    let mut description = time_of_day(schedule, &fields);
+
+    let mut days: Vec<String> = Vec::new();
+    if fields.days_of_month.is_all() && fields.days_of_week.is_all() {
+        if !matches!(fields.hours, Shape::All | Shape::Step(_, _)) {
+            days.push("every day".to_string());
+        }
+    } else {
+        days.extend(days_of_month(&fields.days_of_month));
+        days.extend(days_of_week(&fields.days_of_week, !fields.days_of_month.is_all()));
+    }
+    days.extend(months(&fields.months));
+    days.extend(years(&fields.years));
+    if !days.is_empty() {
+        description.push(' ');
+        description.push_str(&days.join(" "));
+    }
+    description
+}
+
+fn time_of_day(schedule: &Schedule, fields: &Fields) -> String {
+    let seconds: Vec<u32> = schedule.seconds().iter().collect();
+    let minutes: Vec<u32> = schedule.minutes().iter().collect();
+    let hours: Vec<u32> = schedule.hours().iter().collect();
+
+    if seconds.len() * minutes.len() * hours.len() <= 4 {
+        let mut times = Vec::new();
+        for h in &hours {
+            for m in &minutes {
+                for s in &seconds {
+                    times.push(format!("{:02}:{:02}:{:02}", h, m, s));
+                }
+            }
+        }
+        return format!("at {}", join(times, "and"));
+    }
+
+    let mut parts = Vec::new();
+    match &fields.seconds {
+        Shape::All => parts.push("every second".to_string()),
+        Shape::Single(0) => {},
+        Shape::Single(s) if fields.minutes.is_all() => parts.push(format!("at second {} of every minute", s)),
+        Shape::Single(s) => parts.push(format!("at second {}", s)),
+        Shape::Range(a, b) => parts.push(format!("every second from second {} through {}", a, b)),
+        Shape::Step(start, step) => parts.push(step_phrase(*step, "seconds", "second", 0, *start)),
+        Shape::List(values) => parts.push(format!("at seconds {}", numbers(values))),
+    }
+    match &fields.minutes {
+        Shape::All if fields.seconds == Shape::Single(0) => parts.push("every minute".to_string()),
+        Shape::All => {},
+        Shape::Single(m) if fields.hours.is_all() => parts.push(format!("at minute {} of every hour", m)),
+        Shape::Single(m) => parts.push(format!("at minute {}", m)),
+        Shape::Range(a, b) => parts.push(format!("every minute from minute {} through {}", a, b)),
+        Shape::Step(start, step) => parts.push(step_phrase(*step, "minutes", "minute", 0, *start)),
+        Shape::List(values) => parts.push(format!("at minutes {}", numbers(values))),
+    }
+    match &fields.hours {
+        Shape::All => {},
+        Shape::Single(h) => parts.push(format!("between {:02}:00 and {:02}:59", h, h)),
+        Shape::Range(a, b) => parts.push(format!("between {:02}:00 and {:02}:59", a, b)),
+        Shape::Step(start, step) => parts.push(step_phrase(*step, "hours", "hour", 0, *start)),
+        Shape::List(values) => parts.push(format!("during hours {}", numbers(values))),
+    }
+    parts.join(", ")
+}
+
+fn days_of_month(shape: &Shape) -> Option<String> {
+    Some(match shape {
+        Shape::All => return None,
+        Shape::Single(d) => format!("on day {} of the month", d),
+        Shape::Range(a, b) => format!("on days {} through {} of the month", a, b),
+        Shape::Step(start, step) => format!("{} of the month", step_phrase(*step, "days", "day", 1, *start)),
+        Shape::List(values) => format!("on days {} of the month", numbers(values)),
+    })
+}
+
+/// Describes the days of the week, as a restriction on top of days of the
+/// month if `restricts` is set.
+fn days_of_week(shape: &Shape, restricts: bool) -> Option<String> {
+    let days = match shape {
+        Shape::All => return None,
+        Shape::Range(2, 6) => "every weekday".to_string(),
+        Shape::List(values) if *values == [1, 7] => "weekends".to_string(),
+        Shape::Single(d) => format!("{}s", day(*d)),
+        Shape::Range(a, b) => format!("{} through {}", day(*a), day(*b)),
+        Shape::Step(_, _) | Shape::List(_) => join(day_values(shape).into_iter().map(day).collect(), "and"),
+    };
+    Some(if restricts { format!("and only on {}", days) } else { format!("on {}", days) })
+}
+
+fn months(shape: &Shape) -> Option<String> {
+    Some(match shape {
+        Shape::All => return None,
+        Shape::Single(m) => format!("in {}", month(*m)),
+        Shape::Range(a, b) => format!("from {} through {}", month(*a), month(*b)),
+        Shape::Step(1, step) => format!("every {} months", step),
+        Shape::Step(start, step) => format!("every {} months from {}", step, month(*start)),
+        Shape::List(values) => format!("in {}", join(values.iter().map(|m| month(*m)).collect(), "and")),
+    })
+}
+
+fn years(shape: &Shape) -> Option<String> {
+    Some(match shape {
+        Shape::All => return None,
+        Shape::Single(y) => format!("in {}", y),
+        Shape::Range(a, b) => format!("from {} through {}", a, b),
+        Shape::Step(start, step) => format!("every {} years from {}", step, start),
+        Shape::List(values) => format!("in {}", numbers(values)),
+    })
+}
+
+/// Lists what in a schedule is likely not what its author meant.
+pub fn surprises(schedule: &Schedule, expr: &str, syntax: Syntax) -> Vec<String> {
+    check(schedule, expr, syntax, false)
+}
+
+/// Lists the surprises in a crontab line that runs on the days matching either
+/// of its day fields, split into one schedule for each.
+pub fn either_surprises(by_month_day: &Schedule, by_week_day: &Schedule, expr: &str, syntax: Syntax) -> Vec<String> {
+    let mut surprises = check(by_month_day, expr, syntax, true);
+    for surprise in check(by_week_day, expr, syntax, false) {
+        if !surprises.contains(&surprise) {
+            surprises.push(surprise);
+        }
+    }
+    surprises
+}
+
+/// Lists the surprises in `schedule`, which with `month_day_half` set only
+/// covers the days of the month of a crontab line that also runs on days of the week.
+fn check(schedule: &Schedule, expr: &str, syntax: Syntax, month_day_half: bool) -> Vec<String> {
+    let fields = Fields::of(schedule);
+    let mut surprises = Vec::new();
+
+    // Crontab lines restricting both day fields run on days matching either.
+    if !fields.days_of_month.is_all() && !fields.days_of_week.is_all() && syntax::resolve(syntax, expr) != Syntax::Vixie {
+        surprises.push(
+            "both day-of-month and day-of-week are restricted, so this only runs on days matching both. \
+             Classic crontab runs on days matching either"
+                .to_string(),
+        );
+    }
+
+    let first_day = schedule.days_of_month().iter().next().unwrap_or(1);
+    let selected: Vec<u32> = schedule.months().iter().collect();
+    let skipped: Vec<u32> = selected.iter().copied().filter(|m| MONTH_DAYS[*m as usize - 1] < first_day).collect();
+    let never = skipped.len() == selected.len();
+    if never && month_day_half {
+        surprises.push(format!(
+            "{} never has a day {}, so this only runs on the days of the week",
+            join(skipped.into_iter().map(month).collect(), "and"), first_day,
+        ));
+    } else if never {
+        surprises.push(format!("{} never has a day {}, so this never runs", join(skipped.into_iter().map(month).collect(), "and"), first_day));
+    } else if !skipped.is_empty() {
+        surprises.push(format!("skips {}, which have fewer than {} days", join(skipped.into_iter().map(month).collect(), "and"), first_day));
+    }
+    // With the years restricted, whether they are leap years shows in the fire times left.
+    if !never && first_day == 29 && selected.contains(&2) && !schedule.days_of_month().includes(28) && fields.years.is_all() {
+        surprises.push(if month_day_half {
+            "only runs on February 29 in leap years, on top of the days of the week".to_string()
+        } else {
+            "only runs in February of leap years".to_string()
+        });
+    }
+    if !never && !month_day_half && schedule.upcoming(Utc).next().is_none() {
+        surprises.push("has no fire times left, all of its years have passed".to_string());
+    }
+
+    if fields.seconds.is_all() && !(fields.minutes.is_all() && fields.hours.is_all()) {
+        surprises.push("the second field matches every second, so this runs 60 times in each matching minute. Use 0 to run once".to_string());
+    } else if fields.minutes.is_all() && !fields.hours.is_all() {
+        surprises.push("the minute field matches every minute, so this runs 60 times in each matching hour. Use 0 to run once".to_string());
+    }
+
+    for (shape, unit, period, size) in [
+        (&fields.seconds, "second", "minute", 60),
+        (&fields.minutes, "minute", "hour", 60),
+        (&fields.hours, "hour", "day", 24),
+    ] {
+        if let Shape::Step(start, step) = shape {
+            if size % step != 0 {
+                let last = start + (size - 1 - start) / step * step;
+                surprises.push(format!(
+                    "steps of {} {}s do not divide the {} evenly, so {} {} is followed by {} {} after only {} {}s",
+                    step, unit, period, unit, last, unit, start, size - last + start, unit,
+                ));
+            }
+        }
+    }
+
+    let tokens: Vec<&str> = expr.split_whitespace().collect();
+    if !expr.trim().starts_with('@') && syntax::resolve(syntax, expr) != Syntax::Vixie && tokens.len() > 5 && tokens[5].contains(|c: char| c.is_ascii_digit()) {
+        surprises.push(format!(
+            "day-of-week numbers count from Sunday as 1 in this syntax, not 0 as in crontab, so '{}' means {}",
+            tokens[5],
+            join(day_values(&fields.days_of_week).into_iter().map(day).collect(), "and"),
+        ));
+    }
+
+    surprises
+}
+
+fn day_values(shape: &Shape) -> Vec<u32> {
+    match shape {
+        Shape::All => (1..=7).collect(),
+        Shape::Single(d) => vec![*d],
+        Shape::Range(a, b) => (*a..=*b).collect(),
+        Shape::Step(start, step) => (*start..=7).step_by(*step as usize).collect(),
+        Shape::List(values) => values.clone(),
+    }
+}
+
+fn step_phrase(step: u32, units: &str, unit: &str, min: u32, start: u32) -> String {
+    if start == min {
+        format!("every {} {}", step, units)
+    } else {
+        format!("every {} {} from {} {}", step, units, unit, start)
+    }
+}
+
+fn day(d: u32) -> &'static str {
+    DAYS[d as usize - 1]
+}
+
+fn month(m: u32) -> &'static str {
+    MONTHS[m as usize - 1]
+}
+
+fn numbers(values: &[u32]) -> String {
+    join(values.iter().map(|v| v.to_string()).collect(), "and")
+}
+
+/// Joins items like "a, b and c".
+fn join<S: AsRef<str>>(items: Vec<S>, conjunction: &str) -> String {
+    let items: Vec<&str> = items.iter().map(|s| s.as_ref()).collect();
+    match items.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} {} {}", rest.join(", "), conjunction, last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn explain(expr: &str) -> (String, Vec<String>) {
+        match syntax::parse(expr, Syntax::Auto) {
+            Ok(Parsed::Cron(schedule)) => (describe(&schedule), surprises(&schedule, expr, Syntax::Auto)),
+            Ok(Parsed::Either(by_month_day, by_week_day)) => (
+                format!("{}, or {}", describe(&by_month_day), describe(&by_week_day)),
+                either_surprises(&by_month_day, &by_week_day, expr, Syntax::Auto),
+            ),
+            _ => panic!("invalid expression {}", expr),
+        }
+    }
+
+    #[test]
+    fn describes_fields() {
+        assert_eq!(explain("0 30 9 * * Mon-Fri").0, "at 09:30:00 on every weekday");
+        assert_eq!(explain("*/15 * * * *").0, "every 15 minutes");
+        assert_eq!(explain("0 9-17 * * Sat,Sun").0, "at minute 0, between 09:00 and 17:59 on weekends");
+        assert_eq!(explain("@monthly").0, "at 00:00:00 on day 1 of the month");
+        assert_eq!(explain("0 0 12 1 1,7 * 2030").0, "at 12:00:00 on day 1 of the month in January and July in 2030");
+        assert_eq!(explain("0 0 13 * 5").0, "at 00:00:00 on day 13 of the month, or at 00:00:00 on Fridays");
+    }
+
+    #[test]
+    fn flags_surprises() {
+        assert!(explain("0 0 0 13 * Fri").1[0].starts_with("both day-of-month and day-of-week are restricted"));
+        assert!(explain("0 0 13 * 5").1.is_empty());
+        assert_eq!(explain("0 0 30 2 *").1, ["February never has a day 30, so this never runs"]);
+        assert_eq!(explain("0 0 0 29 2 *").1, ["only runs in February of leap years"]);
+        assert_eq!(explain("0 0 0 29 2 * 2024").1, ["has no fire times left, all of its years have passed"]);
+        assert_eq!(explain("0 0 30 2 1").1, ["February never has a day 30, so this only runs on the days of the week"]);
+        assert!(explain("* 9 * * *").1[0].starts_with("the minute field matches every minute"));
+        assert_eq!(
+            explain("0 */7 * * * *").1,
+            ["steps of 7 minutes do not divide the hour evenly, so minute 56 is followed by minute 0 after only 4 minutes"],
+        );
+        assert!(explain("0 0 9 * * 1-5").1[0].ends_with("means Sunday, Monday, Tuesday, Wednesday and Thursday"));
+    }
+}
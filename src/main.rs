@@ -1,25 +1,61 @@
-use clap::{Parser};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use clap::builder::RangedU64ValueParser;
 use colored::*;
-use cron::Schedule;
-use chrono::{Local};
-use log::{info, warn, error};
-use indicatif::{ProgressBar, ProgressStyle};
-use std::{thread, process::{Command as ProcessCommand, Stdio}};
+use chrono::{Local, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::error;
+use std::fmt::Display;
 use std::io::Write;
-use std::str::FromStr;
+use std::path::PathBuf;
 use std::time::{Duration};
 use env_logger::{Builder, Env};
 
+mod datetime;
+mod except;
+mod exit;
+mod explain;
+mod next;
+mod runner;
+mod schedule;
+mod scheduler;
+mod state;
+mod syntax;
+
+use crate::datetime::DateTimeArg;
+use crate::except::Exclusions;
+use crate::exit::CodePattern;
+use crate::explain::ExplainArgs;
+use crate::next::NextArgs;
+use crate::schedule::{Anchor, DstPolicy, Timetable};
+use crate::scheduler::{CatchUp, OverlapPolicy, Scheduler};
+use crate::state::StateFile;
+use crate::syntax::{Parsed, Syntax};
+
+/// Exit code when the cron expression or an exclusion is invalid
+const INVALID_SCHEDULE_CODE: i32 = 4;
+
 #[derive(Parser)]
 #[command(name = "Cron Job Runner")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    subcommand: Option<Command>,
+
     /// Commands to execute
     #[arg(required = true, last = true)]
     command: Vec<String>,
 
-    /// Cron expression to schedule the job
-    #[arg(short = 't', long = "cron")]
-    cron: String,
+    #[command(flatten)]
+    schedule: ScheduleArgs,
+
+    /// Run the command through a shell, $SHELL or /bin/sh unless a path is given.
+    /// The first argument is the script, any further arguments are quoted and appended to it
+    #[arg(long = "shell", value_name = "PATH", num_args = 0..=1)]
+    shell: Option<Option<PathBuf>>,
+
+    /// Like --shell, but start the shell as a login shell so that it reads the profile
+    #[arg(long = "shell-login")]
+    shell_login: bool,
 
     /// Suppress output
     #[arg(short = 'q', long = "quiet")]
@@ -29,9 +65,98 @@ struct Cli {
     #[arg(short = 'x', long = "exit-on-error")]
     exit_on_error: bool,
 
-    /// Ignore these exit codes when --exit-on-error is enabled (comma separated)
-    #[arg(short = 'c', long = "ignored-codes", use_value_delimiter = true)]
-    ignored_codes: Vec<i32>,
+    /// Terminate the command if it runs longer than this (e.g. 30s, 5m), counted as exit code 124
+    #[arg(long = "timeout", value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Grace period between SIGTERM and SIGKILL for timed out commands
+    #[arg(long = "kill-after", value_parser = humantime::parse_duration, default_value = "10s")]
+    kill_after: Duration,
+
+    /// Delay each run by a random time up to this long (e.g. 5m), to spread load across hosts
+    #[arg(long = "jitter", value_parser = humantime::parse_duration)]
+    jitter: Option<Duration>,
+
+    /// Derive the --jitter delay from this string (e.g. the host name) instead of at random,
+    /// so that each run is delayed by the same amount
+    #[arg(long = "splay-seed", requires = "jitter")]
+    splay_seed: Option<String>,
+
+    /// Run once right away, then follow the schedule
+    #[arg(long = "run-at-start", conflicts_with = "run_at_start_if_overdue")]
+    run_at_start: bool,
+
+    /// Run once right away if a fire time has passed since the last successful run in the state file
+    #[arg(long = "run-at-start-if-overdue", requires = "state_file")]
+    run_at_start_if_overdue: bool,
+
+    /// Exit with status 0 after this many runs
+    #[arg(long = "max-runs", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    max_runs: Option<usize>,
+
+    /// Retry a failed run up to this many times, as long as it does not run into the next fire time
+    #[arg(long = "retries", default_value_t = 0)]
+    retries: u32,
+
+    /// Delay before the first retry
+    #[arg(long = "retry-delay", value_parser = humantime::parse_duration, default_value = "10s")]
+    retry_delay: Duration,
+
+    /// Factor to multiply the retry delay by after every retry
+    #[arg(long = "retry-backoff", value_parser = parse_backoff, default_value_t = 2.0)]
+    retry_backoff: f64,
+
+    /// Longest delay between retries
+    #[arg(long = "retry-max-delay", value_parser = humantime::parse_duration, default_value = "1h")]
+    retry_max_delay: Duration,
+
+    /// Exit after this many failed runs in a row
+    #[arg(long = "max-consecutive-failures", value_parser = RangedU64ValueParser::<u32>::new().range(1..))]
+    max_consecutive_failures: Option<u32>,
+
+    /// Pause scheduling for --pause-for after this many failed runs in a row
+    #[arg(long = "pause-after-failures", requires = "pause_for", value_parser = RangedU64ValueParser::<u32>::new().range(1..))]
+    pause_after_failures: Option<u32>,
+
+    /// How long to pause for with --pause-after-failures (e.g. 30m)
+    #[arg(long = "pause-for", requires = "pause_after_failures", value_parser = humantime::parse_duration)]
+    pause_for: Option<Duration>,
+
+    /// How long to wait for active runs to stop after forwarding SIGINT or SIGTERM to them, before killing them
+    #[arg(long = "shutdown-grace", value_parser = humantime::parse_duration, default_value = "10s")]
+    shutdown_grace: Duration,
+
+    /// What to do when a run is due while the previous one is still active
+    #[arg(long = "overlap", value_enum, default_value_t = OverlapPolicy::Skip)]
+    overlap: OverlapPolicy,
+
+    /// Maximum number of runs waiting behind the active one for --overlap queue, or started alongside it for --overlap parallel
+    #[arg(long = "overlap-limit", default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    overlap_limit: usize,
+
+    /// Which runs to make up for when fire times were missed during suspend or a clock change
+    #[arg(long = "catch-up", value_enum, default_value_t = CatchUp::Once)]
+    catch_up: CatchUp,
+
+    /// Maximum number of missed runs to make up for with --catch-up all
+    #[arg(long = "catch-up-limit", default_value_t = 10, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    catch_up_limit: usize,
+
+    /// File to remember the last run in, so that runs missed while croncycle was not running are caught up on
+    #[arg(long = "state-file")]
+    state_file: Option<PathBuf>,
+
+    /// Name of the job in the state file, defaults to the cron expression and command
+    #[arg(long = "job-name", requires = "state_file")]
+    job_name: Option<String>,
+
+    /// Ignore these exit codes or signals (e.g. 1-5,10,SIGTERM) for --exit-on-error and the limits on consecutive failures
+    #[arg(short = 'c', long = "ignored-codes", use_value_delimiter = true, value_parser = CodePattern::parse)]
+    ignored_codes: Vec<CodePattern>,
+
+    /// Count these exit codes or signals as success, e.g. 1 for grep finding nothing (same syntax as --ignored-codes)
+    #[arg(long = "success-codes", use_value_delimiter = true, value_parser = CodePattern::parse)]
+    success_codes: Vec<CodePattern>,
 
     /// Disable color output
     #[arg(short = 'b', long = "no-color")]
@@ -42,20 +167,156 @@ struct Cli {
     enable_stdin: bool,
 
     /// Redirect command stderr to stdout
-    #[arg(short = 'r', long = "stderr-to-stdout")]
+    #[arg(short = 'r', long = "stderr-to-stdout", conflicts_with = "inherit_stderr")]
     stderr_to_stdout: bool,
 
+    /// Leave command stderr on the terminal instead of logging it as warnings
+    #[arg(long = "inherit-stderr")]
+    inherit_stderr: bool,
+
+    /// Prefix for command stderr lines forwarded to the log
+    #[arg(long = "stderr-prefix", default_value = "")]
+    stderr_prefix: String,
+
     /// Disable command output
     #[arg(short = 's', long = "no-output")]
     no_output: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Print the next fire times of a schedule without running anything
+    Next(NextArgs),
+    /// Describe a cron expression in plain English and point out surprises in it
+    Explain(ExplainArgs),
+}
+
+/// When to run, shared by running jobs and previewing schedules
+#[derive(Args)]
+#[command(group(ArgGroup::new("timetable").required(true).args(["cron", "every"])))]
+struct ScheduleArgs {
+    /// Cron expression to schedule the job, or one of the macros @reboot, @hourly, @daily, @weekly, @monthly and @yearly.
+    /// Repeat to run whenever any of the expressions matches
+    #[arg(short = 't', long = "cron")]
+    cron: Vec<String>,
+
+    /// Syntax of the cron expression
+    #[arg(long = "syntax", value_enum, default_value_t = Syntax::Auto)]
+    syntax: Syntax,
+
+    /// Run the job at a fixed interval (e.g. 90s, 2h15m) instead of a cron expression
+    #[arg(long = "every", value_parser = parse_interval)]
+    every: Option<Duration>,
+
+    /// Line --every up with the wall clock, e.g. every 15m runs at :00, :15, :30 and :45
+    #[arg(long = "align", requires = "every", conflicts_with = "from")]
+    align: bool,
+
+    /// Count --every intervals from this date and time instead of from startup
+    #[arg(long = "from", requires = "every", value_parser = DateTimeArg::parse)]
+    from: Option<DateTimeArg>,
+
+    /// Do not run before this date and time, e.g. 2024-05-01 09:00
+    #[arg(long = "not-before", value_parser = DateTimeArg::parse)]
+    not_before: Option<DateTimeArg>,
+
+    /// Exit with status 0 once the next run would be after this date and time
+    #[arg(long = "until", value_parser = DateTimeArg::parse)]
+    until: Option<DateTimeArg>,
+
+    /// Skip fire times matching this cron expression, e.g. "* * 24-26 12 *". Repeat for several
+    #[arg(long = "except", value_name = "CRON")]
+    except: Vec<String>,
+
+    /// Skip fire times on the dates in this file, either one YYYY-MM-DD per line or an iCalendar (.ics) file
+    #[arg(long = "except-dates", value_name = "FILE")]
+    except_dates: Vec<PathBuf>,
+
+    /// Time zone (IANA name, e.g. Europe/Berlin) to evaluate the cron expression in, defaults to local time
+    #[arg(long = "tz", conflicts_with = "utc")]
+    tz: Option<Tz>,
+
+    /// Evaluate the cron expression in UTC, shortcut for --tz UTC
+    #[arg(long = "utc")]
+    utc: bool,
+
+    /// How to handle fire times that do not exist or occur twice due to daylight saving time
+    #[arg(long = "dst", value_enum, default_value_t = DstPolicy::RunOnce)]
+    dst: DstPolicy,
+}
+
+impl ScheduleArgs {
+    /// Builds the timetable, exiting if the cron expression is invalid.
+    fn timetable<Z: TimeZone>(&self, tz: &Z) -> Timetable {
+        if let Some(interval) = self.every {
+            let anchor = if self.align {
+                Anchor::WallClock
+            } else if let Some(from) = &self.from {
+                Anchor::From(from.resolve(tz).with_timezone(&Utc))
+            } else {
+                Anchor::Startup(Utc::now())
+            };
+            return Timetable::Every { interval: TimeDelta::from_std(interval).expect("interval out of range"), anchor };
+        }
+
+        let mut timetables: Vec<Timetable> = self.cron.iter().map(|cron| match syntax::parse(cron, self.syntax) {
+            Ok(parsed) => Timetable::from_parsed(cron, parsed, self.dst),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(INVALID_SCHEDULE_CODE);
+            },
+        }).collect();
+
+        if timetables.len() == 1 {
+            timetables.remove(0)
+        } else {
+            Timetable::Any(timetables)
+        }
+    }
+
+    /// Reads the fire times to skip, exiting if an exclusion is invalid.
+    fn exclusions(&self) -> Exclusions {
+        let mut exclusions = Exclusions::default();
+        for expr in &self.except {
+            match syntax::parse(expr, self.syntax) {
+                Ok(Parsed::Cron(schedule)) => {
+                    let whole_minute = syntax::resolve(self.syntax, expr) == Syntax::Vixie;
+                    exclusions.add_cron(expr.clone(), schedule, whole_minute);
+                },
+                Ok(Parsed::Either(by_month_day, by_week_day)) => {
+                    exclusions.add_cron(expr.clone(), by_month_day, true);
+                    exclusions.add_cron(expr.clone(), by_week_day, true);
+                },
+                Ok(Parsed::Reboot) => {
+                    error!("@reboot cannot be used with --except");
+                    std::process::exit(INVALID_SCHEDULE_CODE);
+                },
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(INVALID_SCHEDULE_CODE);
+                },
+            }
+        }
+        for path in &self.except_dates {
+            match except::load_dates(path) {
+                Ok(dates) => exclusions.add_dates(path.clone(), dates),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(INVALID_SCHEDULE_CODE);
+                },
+            }
+        }
+        exclusions
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
+    let no_color = cli.no_color;
     let mut builder = Builder::from_env(Env::default().default_filter_or(if cli.quiet { "error" } else { "info" }));
     builder.format(move |buf, record| {
         let level = record.level();
-        let message = if cli.no_color {
+        let message = if no_color {
             format!("{}: {}", level, record.args())
         } else {
             format!("{}: {}", level.to_string().color(match level {
@@ -69,68 +330,65 @@ fn main() {
     });
     builder.init();
 
-    let schedule = Schedule::from_str(&cli.cron);
-
-    if let Err(_) = schedule {
-        error!("Failed to parse cron expression");
-        std::process::exit(1);
+    match &cli.subcommand {
+        Some(Command::Next(args)) => {
+            let schedule = &args.schedule;
+            if schedule.utc {
+                next::print(args, Utc);
+            } else if let Some(tz) = schedule.tz {
+                next::print(args, tz);
+            } else {
+                next::print(args, Local);
+            }
+            return;
+        },
+        Some(Command::Explain(args)) => {
+            explain::print(args);
+            return;
+        },
+        None => {},
     }
 
-    let schedule = schedule.unwrap();
-
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(ProgressStyle::default_spinner()
-        .tick_strings(&["⠁", "⠂", "⠄", "⡀", "⢀", "⠠", "⠐", "⠈"])
-        .template("{spinner:.green} {msg}").expect("Failed to set spinner style"));
-
-    loop {
-        let next_run = schedule.upcoming(Local).next().unwrap();
-        let now = Local::now();
-
-        if next_run <= now {
-            spinner.set_message("Next run is in the past, checking again...".to_string());
-            warn!("Next run is in the past: {:?}", next_run);
-            continue;
-        }
-
-        spinner.set_message(format!("Next run at {:?}", next_run));
-
-        while Local::now() < next_run {
-            spinner.tick();
-            thread::sleep(Duration::from_millis(100)); // Update every 100 milliseconds
-        }
+    let schedule = &cli.schedule;
+    if schedule.utc {
+        start(&cli, Utc);
+    } else if let Some(tz) = schedule.tz {
+        start(&cli, tz);
+    } else {
+        start(&cli, Local);
+    }
+}
 
-        spinner.set_message("Running job...".to_string());
-        let mut command_proc = ProcessCommand::new(&cli.command[0]);
-        command_proc.args(&cli.command[1..]);
+fn start<Z: TimeZone>(cli: &Cli, tz: Z) -> !
+where
+    Z::Offset: Display,
+{
+    let timetable = cli.schedule.timetable(&tz);
+    let exclusions = cli.schedule.exclusions();
 
-        if cli.enable_stdin {
-            command_proc.stdin(Stdio::inherit());
-        } else {
-            command_proc.stdin(Stdio::null());
-        }
+    let state = cli.state_file.clone().map(|path| {
+        let key = cli.job_name.clone().unwrap_or_else(|| format!("{} -- {}", timetable, cli.command.join(" ")));
+        StateFile::new(path, key)
+    });
 
-        if cli.no_output {
-            command_proc.stdout(Stdio::null());
-        } else {
-            command_proc.stdout(Stdio::inherit());
-        }
+    Scheduler::new(cli, state).run(&timetable, &exclusions, tz)
+}
 
-        if cli.stderr_to_stdout {
-            command_proc.stderr(Stdio::inherit());
-        } else {
-            command_proc.stderr(Stdio::piped());
-        }
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let interval = humantime::parse_duration(s).map_err(|e| e.to_string())?;
+    if interval < Duration::from_millis(1) {
+        return Err("interval must be at least 1ms".to_string());
+    }
+    if TimeDelta::from_std(interval).is_err() {
+        return Err("interval is too long".to_string());
+    }
+    Ok(interval)
+}
 
-        match command_proc.status() {
-            Ok(status) if status.success() => info!("Command exited with status {}", status),
-            Ok(status) => {
-                spinner.set_message(format!("Error: Command exited with status {}", status));
-                if cli.exit_on_error && !cli.ignored_codes.contains(&status.code().unwrap_or_default()) {
-                    std::process::exit(status.code().unwrap_or_default());
-                }
-            },
-            Err(e) => error!("Failed to execute command: {}", e),
-        }
+fn parse_backoff(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(factor) if factor >= 1.0 && factor.is_finite() => Ok(factor),
+        Ok(_) => Err("backoff factor must be at least 1".to_string()),
+        Err(e) => Err(e.to_string()),
     }
 }
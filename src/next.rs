@@ -0,0 +1,90 @@
+use chrono::{SubsecRound, TimeDelta, TimeZone, Utc};
+use clap::{Args, ValueEnum};
+use serde_json::json;
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::datetime::DateTimeArg;
+use crate::schedule::Upcoming;
+use crate::ScheduleArgs;
+
+#[derive(Args)]
+pub struct NextArgs {
+    #[command(flatten)]
+    pub schedule: ScheduleArgs,
+
+    /// Number of fire times to print
+    #[arg(short = 'n', long = "count", default_value_t = 10)]
+    count: usize,
+
+    /// Print fire times after this date and time instead of after now
+    #[arg(long = "after", value_parser = DateTimeArg::parse)]
+    after: Option<DateTimeArg>,
+
+    /// Output format
+    #[arg(long = "format", value_enum, default_value_t = Format::Table)]
+    format: Format,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Table,
+    Json,
+}
+
+/// Prints the next fire times of the schedule in `args`.
+pub fn print<Z: TimeZone>(args: &NextArgs, tz: Z)
+where
+    Z::Offset: Display,
+{
+    let timetable = args.schedule.timetable(&tz);
+    let after = match &args.after {
+        Some(after) => after.resolve(&tz),
+        None => Utc::now().with_timezone(&tz),
+    };
+    let exclusions = args.schedule.exclusions();
+    let not_before = args.schedule.not_before.as_ref().map(|t| t.resolve(&tz));
+    let until = args.schedule.until.as_ref().map(|t| t.resolve(&tz));
+    let start = match &not_before {
+        Some(not_before) if *not_before > after => not_before.clone() - TimeDelta::milliseconds(1),
+        _ => after.clone(),
+    };
+    let times: Vec<_> = Upcoming::after(&timetable, &start)
+        .take_while(|t| until.as_ref().is_none_or(|until| t <= until))
+        .filter(|t| exclusions.reason(t).is_none())
+        .take(args.count)
+        .collect();
+
+    match args.format {
+        Format::Json => {
+            let times: Vec<_> = times
+                .iter()
+                .map(|t| match timetable.trigger(t) {
+                    Some(trigger) => json!({ "time": t.to_rfc3339(), "timestamp": t.timestamp(), "trigger": trigger }),
+                    None => json!({ "time": t.to_rfc3339(), "timestamp": t.timestamp() }),
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&times).expect("failed to serialize fire times"));
+        },
+        Format::Table => {
+            if timetable.runs_at_startup() {
+                println!("@reboot runs once when croncycle starts");
+                if times.is_empty() {
+                    return;
+                }
+            }
+            if times.is_empty() {
+                println!("No fire times after {}", after.trunc_subsecs(0));
+                return;
+            }
+
+            let width = times.len().to_string().len();
+            for (i, t) in times.iter().enumerate() {
+                let delta = t.clone().signed_duration_since(after.clone());
+                let delta = Duration::from_secs(delta.num_seconds().max(0) as u64);
+                let trigger = timetable.trigger(t).map(|t| format!("  ({})", t)).unwrap_or_default();
+                println!("{:>width$}  {}  in {}{}", i + 1, t, humantime::format_duration(delta), trigger, width = width);
+            }
+        },
+    }
+}
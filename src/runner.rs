@@ -0,0 +1,294 @@
+use log::warn;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Child, ChildStderr, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use wait_timeout::ChildExt;
+
+/// Exit code reported for runs that were stopped because they hit --timeout
+pub const TIMED_OUT_CODE: i32 = 124;
+
+/// Exit code reported for runs whose command could not be started, as shells do
+pub const SPAWN_FAILED_CODE: i32 = 127;
+
+/// Longest time to wait for the stderr of a command that has exited to be closed
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub enum Outcome {
+    /// The command exited on its own
+    Exited(ExitStatus),
+    /// The command exceeded the timeout and was terminated
+    TimedOut,
+}
+
+/// How long a run may take before it is stopped
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub kill_after: Duration,
+    /// Whether the command leads a process group of its own, which is then stopped as a whole
+    pub group: bool,
+}
+
+/// Result of a run started with [`spawn`]
+pub struct Finished {
+    pub id: u64,
+    pub result: io::Result<Outcome>,
+}
+
+/// Starts the command and waits for it on a background thread, passing the
+/// outcome to `notify` once it is done. Returns the process id of the command.
+///
+/// A command running longer than the timeout first receives SIGTERM and, if
+/// it is still running after `kill_after`, SIGKILL, along with the rest of its
+/// process group if it has one of its own. Piped stderr is forwarded
+/// to the log line by line, prefixed with `stderr_prefix`.
+pub fn spawn(
+    command: &mut Command,
+    id: u64,
+    limits: Limits,
+    stderr_prefix: &str,
+    notify: impl FnOnce(Finished) + Send + 'static,
+) -> io::Result<u32> {
+    let mut child = command.spawn()?;
+    let pid = child.id();
+
+    let forwarder = child.stderr.take().map(|stderr| {
+        let prefix = stderr_prefix.to_string();
+        thread::spawn(move || forward_stderr(stderr, &prefix))
+    });
+
+    thread::spawn(move || {
+        let result = wait(&mut child, limits);
+        if let Some(forwarder) = forwarder {
+            join_forwarder(forwarder);
+        }
+        notify(Finished { id, result });
+    });
+
+    Ok(pid)
+}
+
+/// Builds a shell script from the command line: the first argument is taken
+/// as is, the remaining ones are quoted so the shell sees them as single words.
+pub fn shell_command(command: &[String]) -> String {
+    let mut script = command[0].clone();
+    for arg in &command[1..] {
+        script.push(' ');
+        script.push_str(&shell_quote(arg));
+    }
+    script
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn forward_stderr(stderr: ChildStderr, prefix: &str) {
+    for line in BufReader::new(stderr).split(b'\n') {
+        match line {
+            Ok(line) => warn!("{}{}", prefix, String::from_utf8_lossy(&line).trim_end_matches('\r')),
+            Err(e) => {
+                warn!("Failed to read command stderr: {}", e);
+                break;
+            },
+        }
+    }
+}
+
+/// Waits for the last stderr lines of a command that has exited to be logged,
+/// but at most [`STDERR_DRAIN_TIMEOUT`] as processes it started in the
+/// background may keep the pipe open.
+fn join_forwarder(forwarder: JoinHandle<()>) {
+    let deadline = Instant::now() + STDERR_DRAIN_TIMEOUT;
+    while !forwarder.is_finished() {
+        if Instant::now() >= deadline {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let _ = forwarder.join();
+}
+
+/// Returns a handle to croncycle's stdout that a command can write to.
+#[cfg(unix)]
+pub fn stdout() -> io::Result<Stdio> {
+    use std::os::fd::AsFd;
+    Ok(io::stdout().as_fd().try_clone_to_owned()?.into())
+}
+
+/// Returns a handle to croncycle's stdout that a command can write to.
+#[cfg(windows)]
+pub fn stdout() -> io::Result<Stdio> {
+    use std::os::windows::io::AsHandle;
+    Ok(io::stdout().as_handle().try_clone_to_owned()?.into())
+}
+
+fn wait(child: &mut Child, limits: Limits) -> io::Result<Outcome> {
+    let timeout = match limits.timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(Outcome::Exited),
+    };
+
+    if let Some(status) = child.wait_timeout(timeout)? {
+        return Ok(Outcome::Exited(status));
+    }
+
+    warn!("Command timed out after {}, terminating", humantime::format_duration(timeout));
+    let pid = child.id();
+    terminate(pid, limits.group).or_else(|_| child.kill())?;
+
+    let deadline = Instant::now() + limits.kill_after;
+    if child.wait_timeout(limits.kill_after)?.is_none() {
+        warn!("Command still running after {}, killing", humantime::format_duration(limits.kill_after));
+        kill(pid, limits.group).or_else(|_| child.kill())?;
+        child.wait()?;
+    } else if limits.group && !wait_group(pid, deadline) {
+        // Processes the command started may ignore SIGTERM and outlive it.
+        warn!("Processes started by the command still running after {}, killing", humantime::format_duration(limits.kill_after));
+        kill(pid, true)?;
+    }
+
+    Ok(Outcome::TimedOut)
+}
+
+/// Asks the process to stop by sending SIGTERM.
+#[cfg(unix)]
+pub fn terminate(pid: u32, group: bool) -> io::Result<()> {
+    signal(pid, group, libc::SIGTERM)
+}
+
+/// Forcibly stops the process by sending SIGKILL.
+#[cfg(unix)]
+pub fn kill(pid: u32, group: bool) -> io::Result<()> {
+    signal(pid, group, libc::SIGKILL)
+}
+
+/// Sends the signal with number `signal` to the process, or with `group` set
+/// to the whole process group it leads. Processes that are gone already are
+/// not an error.
+#[cfg(unix)]
+pub fn signal(pid: u32, group: bool, signal: i32) -> io::Result<()> {
+    let pid = if group { -(pid as libc::pid_t) } else { pid as libc::pid_t };
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// Waits until the process group led by `pid` has no processes left, or
+/// `deadline` has passed. Returns whether the group is gone.
+#[cfg(unix)]
+pub fn wait_group(pid: u32, deadline: Instant) -> bool {
+    loop {
+        if unsafe { libc::kill(-(pid as libc::pid_t), 0) } != 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Calls `f` with the signal number whenever croncycle receives SIGINT or
+/// SIGTERM, instead of letting the signal end croncycle.
+#[cfg(unix)]
+pub fn on_shutdown_signal(f: impl Fn(i32) + Send + 'static) -> io::Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([libc::SIGINT, libc::SIGTERM])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            f(signal);
+        }
+    });
+    Ok(())
+}
+
+/// Signals are left to their default handling on other platforms.
+#[cfg(not(unix))]
+pub fn signal(_pid: u32, _group: bool, _signal: i32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "signals are only supported on unix"))
+}
+
+/// Process groups are only used on unix.
+#[cfg(not(unix))]
+pub fn wait_group(_pid: u32, _deadline: Instant) -> bool {
+    true
+}
+
+#[cfg(not(unix))]
+pub fn on_shutdown_signal(_f: impl Fn(i32) + Send + 'static) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn terminate(_pid: u32, _group: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "terminating commands is only supported on unix"))
+}
+
+#[cfg(not(unix))]
+pub fn kill(_pid: u32, _group: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "killing commands is only supported on unix"))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::process::CommandExt;
+    use std::sync::mpsc;
+
+    /// Processes in the process group `pgid` that have not exited yet.
+    fn live_members(pgid: u32) -> Vec<u32> {
+        let mut members = Vec::new();
+        for entry in fs::read_dir("/proc").unwrap().flatten() {
+            let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+                continue;
+            };
+            let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+                continue;
+            };
+            // Fields after the parenthesized command name: state, ppid, pgrp, ...
+            let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 1..].split_whitespace().collect();
+            if fields[2] == pgid.to_string() && fields[0] != "Z" && fields[0] != "X" {
+                members.push(pid);
+            }
+        }
+        members
+    }
+
+    #[test]
+    fn timeout_stops_whole_process_group() {
+        let mut command = Command::new("/bin/sh");
+        // A shell pipeline, and a process ignoring SIGTERM that outlives the shell.
+        command
+            .arg("-c")
+            .arg("(trap '' TERM; exec sleep 97) & sleep 177 | sleep 178")
+            .process_group(0)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        let limits = Limits { timeout: Some(Duration::from_millis(200)), kill_after: Duration::from_millis(500), group: true };
+        let (tx, rx) = mpsc::channel();
+        let pid = spawn(&mut command, 0, limits, "", move |finished| tx.send(finished).unwrap()).unwrap();
+
+        let finished = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(finished.result, Ok(Outcome::TimedOut)));
+
+        // The sleeps are reaped by whoever inherits them, which may take a moment.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !live_members(pid).is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        let left = live_members(pid);
+        let _ = kill(pid, true);
+        assert_eq!(left, Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,387 @@
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, Offset, TimeDelta, TimeZone, Utc};
+use clap::ValueEnum;
+use cron::Schedule;
+use std::fmt;
+
+use crate::syntax::Parsed;
+
+/// How to handle fire times that fall into a daylight-saving transition
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DstPolicy {
+    /// Skip local times that do not exist or occur twice
+    Skip,
+    /// Run nonexistent times once when the clock jumps forward, and repeated times only on their first occurrence
+    RunOnce,
+    /// Like run-once, but run repeated times on both of their occurrences
+    RunBoth,
+}
+
+/// When a job runs
+pub enum Timetable {
+    /// Whenever the wall clock matches a cron expression, `expr` as given on the command line
+    Cron { expr: String, schedule: Box<Schedule>, dst: DstPolicy },
+    /// Whenever the wall clock matches a crontab line restricting both day
+    /// fields, on the days matching either of them
+    CrontabDays { expr: String, by_month_day: Box<Schedule>, by_week_day: Box<Schedule>, dst: DstPolicy },
+    /// At a fixed interval
+    Every { interval: TimeDelta, anchor: Anchor },
+    /// Only once, when croncycle starts
+    Reboot,
+    /// Whenever any of several timetables fires, at most once per instant
+    Any(Vec<Timetable>),
+}
+
+/// Where the intervals of [`Timetable::Every`] are counted from
+pub enum Anchor {
+    /// The time croncycle started
+    Startup(DateTime<Utc>),
+    /// A given time, with no runs before it
+    From(DateTime<Utc>),
+    /// Midnight on 1970-01-01 in the time zone, so that runs line up with the wall clock
+    WallClock,
+}
+
+impl fmt::Display for Timetable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timetable::Cron { expr, .. } | Timetable::CrontabDays { expr, .. } => write!(f, "{}", expr),
+            Timetable::Every { interval, .. } => {
+                write!(f, "every {}", humantime::format_duration(interval.to_std().unwrap_or_default()))
+            },
+            Timetable::Reboot => write!(f, "@reboot"),
+            Timetable::Any(timetables) => {
+                let timetables: Vec<String> = timetables.iter().map(|t| t.to_string()).collect();
+                write!(f, "{}", timetables.join(", "))
+            },
+        }
+    }
+}
+
+impl Timetable {
+    /// Builds the timetable of the cron expression `expr`, parsed into `parsed`.
+    pub fn from_parsed(expr: &str, parsed: Parsed, dst: DstPolicy) -> Self {
+        match parsed {
+            Parsed::Cron(schedule) => Timetable::Cron { expr: expr.trim().to_string(), schedule, dst },
+            Parsed::Either(by_month_day, by_week_day) => {
+                Timetable::CrontabDays { expr: expr.trim().to_string(), by_month_day, by_week_day, dst }
+            },
+            Parsed::Reboot => Timetable::Reboot,
+        }
+    }
+
+    /// Whether the job runs once when croncycle starts.
+    pub fn runs_at_startup(&self) -> bool {
+        match self {
+            Timetable::Reboot => true,
+            Timetable::Any(timetables) => timetables.iter().any(Timetable::runs_at_startup),
+            _ => false,
+        }
+    }
+
+    /// Names the timetables of a [`Timetable::Any`] that fire at `t`, `None`
+    /// for a single timetable.
+    pub fn trigger<Z: TimeZone>(&self, t: &DateTime<Z>) -> Option<String> {
+        let Timetable::Any(timetables) = self else {
+            return None;
+        };
+        let just_before = t.clone() - TimeDelta::milliseconds(1);
+        let fired: Vec<String> = timetables
+            .iter()
+            .filter(|timetable| Upcoming::after(timetable, &just_before).next().as_ref() == Some(t))
+            .map(|timetable| timetable.to_string())
+            .collect();
+        Some(fired.join(", "))
+    }
+}
+
+/// Iterator over the fire times of a [`Timetable`] in a time zone.
+///
+/// Cron expressions are matched against wall-clock time, so candidates are
+/// generated as naive local times first and then mapped into the zone,
+/// resolving daylight-saving transitions according to a [`DstPolicy`].
+pub struct Upcoming<'a, Z: TimeZone> {
+    timetable: &'a Timetable,
+    tz: Z,
+    after: DateTime<Z>,
+}
+
+impl<'a, Z: TimeZone> Upcoming<'a, Z> {
+    pub fn after(timetable: &'a Timetable, after: &DateTime<Z>) -> Self {
+        Upcoming {
+            timetable,
+            tz: after.timezone(),
+            after: after.clone(),
+        }
+    }
+
+    fn next_after(&self) -> Option<DateTime<Z>> {
+        match self.timetable {
+            Timetable::Cron { schedule, dst, .. } => self.next_cron(schedule, *dst),
+            Timetable::CrontabDays { by_month_day, by_week_day, dst, .. } => {
+                [self.next_cron(by_month_day, *dst), self.next_cron(by_week_day, *dst)].into_iter().flatten().min()
+            },
+            Timetable::Every { interval, anchor } => self.next_interval(*interval, anchor),
+            Timetable::Reboot => None,
+            Timetable::Any(timetables) => timetables
+                .iter()
+                .filter_map(|timetable| Upcoming::after(timetable, &self.after).next())
+                .min(),
+        }
+    }
+
+    fn next_interval(&self, interval: TimeDelta, anchor: &Anchor) -> Option<DateTime<Z>> {
+        let step = interval.num_milliseconds();
+        let (origin, bounded) = match anchor {
+            Anchor::Startup(t) => (t.timestamp_millis(), false),
+            Anchor::From(t) => (t.timestamp_millis(), true),
+            Anchor::WallClock => (-offset_seconds(&self.tz, &self.after.naive_utc()) * 1000, false),
+        };
+
+        let mut n = (self.after.timestamp_millis() - origin).div_euclid(step) + 1;
+        if bounded {
+            n = n.max(0);
+        }
+
+        self.tz.timestamp_millis_opt(origin.checked_add(n.checked_mul(step)?)?).single()
+    }
+
+    fn next_cron(&self, schedule: &Schedule, policy: DstPolicy) -> Option<DateTime<Z>> {
+        // A wall-clock time may map to an instant later than `after` even if it
+        // reads earlier, when the clock has just been turned back. Start from the
+        // earliest wall-clock reading of `after` under any nearby offset.
+        let after_utc = self.after.naive_utc();
+        let min_offset = [after_utc - Duration::days(1), after_utc, after_utc + Duration::days(1)]
+            .iter()
+            .map(|t| offset_seconds(&self.tz, t))
+            .min()
+            .unwrap_or_default();
+        let start = Utc.from_utc_datetime(&(after_utc + Duration::seconds(min_offset)));
+
+        for candidate in schedule.after(&start) {
+            let naive = candidate.naive_utc();
+            let resolved = match self.tz.from_local_datetime(&naive) {
+                LocalResult::Single(t) => vec![t],
+                LocalResult::Ambiguous(first, second) => match policy {
+                    DstPolicy::Skip => vec![],
+                    DstPolicy::RunOnce => vec![first],
+                    DstPolicy::RunBoth => vec![first, second],
+                },
+                LocalResult::None => match policy {
+                    DstPolicy::Skip => vec![],
+                    DstPolicy::RunOnce | DstPolicy::RunBoth => vec![gap_end(&self.tz, &naive)],
+                },
+            };
+            if let Some(t) = resolved.into_iter().find(|t| *t > self.after) {
+                return Some(t);
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a, Z: TimeZone> Iterator for Upcoming<'a, Z> {
+    type Item = DateTime<Z>;
+
+    fn next(&mut self) -> Option<DateTime<Z>> {
+        let next = self.next_after()?;
+        self.after = next.clone();
+        Some(next)
+    }
+}
+
+fn offset_seconds<Z: TimeZone>(tz: &Z, utc: &NaiveDateTime) -> i64 {
+    tz.offset_from_utc_datetime(utc).fix().local_minus_utc() as i64
+}
+
+/// Returns the first instant after the daylight-saving gap containing `naive`.
+pub fn gap_end<Z: TimeZone>(tz: &Z, naive: &NaiveDateTime) -> DateTime<Z> {
+    let before = offset_seconds(tz, &(*naive - Duration::days(1)));
+    let after = offset_seconds(tz, &(*naive + Duration::days(1)));
+
+    // The transition happened somewhere in (naive - after, naive - before].
+    // Before it the wall clock reads at most `naive`, after it later than `naive`.
+    let mut lo = naive.and_utc().timestamp() - after;
+    let mut hi = naive.and_utc().timestamp() - before;
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if tz.timestamp_opt(mid, 0).unwrap().naive_local() > *naive {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+
+    tz.timestamp_opt(hi, 0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+    use std::str::FromStr;
+
+    fn upcoming(expr: &str, dst: DstPolicy, after: DateTime<chrono_tz::Tz>, n: usize) -> Vec<String> {
+        let timetable = Timetable::Cron { expr: expr.to_string(), schedule: Box::new(Schedule::from_str(expr).unwrap()), dst };
+        Upcoming::after(&timetable, &after)
+            .take(n)
+            .map(|t| t.to_rfc3339())
+            .collect()
+    }
+
+    // 2024-03-31 02:00 CET jumps to 03:00 CEST in Berlin.
+    fn spring_forward_eve() -> DateTime<chrono_tz::Tz> {
+        Berlin.with_ymd_and_hms(2024, 3, 30, 12, 0, 0).unwrap()
+    }
+
+    // 2024-10-27 03:00 CEST falls back to 02:00 CET in Berlin.
+    fn fall_back_eve() -> DateTime<chrono_tz::Tz> {
+        Berlin.with_ymd_and_hms(2024, 10, 26, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn spring_forward_skip() {
+        assert_eq!(
+            upcoming("0 30 2 * * *", DstPolicy::Skip, spring_forward_eve(), 2),
+            ["2024-04-01T02:30:00+02:00", "2024-04-02T02:30:00+02:00"],
+        );
+    }
+
+    #[test]
+    fn spring_forward_runs_once_at_jump() {
+        for policy in [DstPolicy::RunOnce, DstPolicy::RunBoth] {
+            assert_eq!(
+                upcoming("0 30 2 * * *", policy, spring_forward_eve(), 2),
+                ["2024-03-31T03:00:00+02:00", "2024-04-01T02:30:00+02:00"],
+            );
+        }
+    }
+
+    #[test]
+    fn spring_forward_collapses_gap() {
+        assert_eq!(
+            upcoming("0 */20 1-3 31 3 *", DstPolicy::RunOnce, spring_forward_eve(), 7),
+            [
+                "2024-03-31T01:00:00+01:00",
+                "2024-03-31T01:20:00+01:00",
+                "2024-03-31T01:40:00+01:00",
+                "2024-03-31T03:00:00+02:00",
+                "2024-03-31T03:20:00+02:00",
+                "2024-03-31T03:40:00+02:00",
+                "2025-03-31T01:00:00+02:00",
+            ],
+        );
+    }
+
+    #[test]
+    fn fall_back_skip() {
+        assert_eq!(
+            upcoming("0 30 2 * * *", DstPolicy::Skip, fall_back_eve(), 2),
+            ["2024-10-28T02:30:00+01:00", "2024-10-29T02:30:00+01:00"],
+        );
+    }
+
+    #[test]
+    fn fall_back_run_once() {
+        assert_eq!(
+            upcoming("0 30 2 * * *", DstPolicy::RunOnce, fall_back_eve(), 2),
+            ["2024-10-27T02:30:00+02:00", "2024-10-28T02:30:00+01:00"],
+        );
+    }
+
+    #[test]
+    fn fall_back_run_both() {
+        assert_eq!(
+            upcoming("0 30 2 * * *", DstPolicy::RunBoth, fall_back_eve(), 3),
+            [
+                "2024-10-27T02:30:00+02:00",
+                "2024-10-27T02:30:00+01:00",
+                "2024-10-28T02:30:00+01:00",
+            ],
+        );
+    }
+
+    #[test]
+    fn fall_back_run_both_resumes_inside_repeated_hour() {
+        let after = Berlin.with_ymd_and_hms(2024, 10, 27, 2, 50, 0).earliest().unwrap();
+        assert_eq!(
+            upcoming("0 */20 2-3 27 10 *", DstPolicy::RunBoth, after, 4),
+            [
+                "2024-10-27T02:00:00+01:00",
+                "2024-10-27T02:20:00+01:00",
+                "2024-10-27T02:40:00+01:00",
+                "2024-10-27T03:00:00+01:00",
+            ],
+        );
+    }
+
+    fn every(interval: i64, anchor: Anchor, after: DateTime<chrono_tz::Tz>, n: usize) -> Vec<String> {
+        let timetable = Timetable::Every { interval: TimeDelta::minutes(interval), anchor };
+        Upcoming::after(&timetable, &after)
+            .take(n)
+            .map(|t| t.to_rfc3339())
+            .collect()
+    }
+
+    #[test]
+    fn every_aligns_with_wall_clock() {
+        let after = Berlin.with_ymd_and_hms(2024, 5, 1, 9, 7, 30).unwrap();
+        assert_eq!(
+            every(15, Anchor::WallClock, after, 3),
+            ["2024-05-01T09:15:00+02:00", "2024-05-01T09:30:00+02:00", "2024-05-01T09:45:00+02:00"],
+        );
+    }
+
+    #[test]
+    fn every_starts_at_from() {
+        let from = Berlin.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        let after = Berlin.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        assert_eq!(
+            every(90, Anchor::From(from.with_timezone(&Utc)), after, 3),
+            ["2024-05-01T10:00:00+02:00", "2024-05-01T11:30:00+02:00", "2024-05-01T13:00:00+02:00"],
+        );
+    }
+
+    #[test]
+    fn crontab_runs_on_either_day_field() {
+        let parsed = crate::syntax::parse("0 0 1 * 1", crate::syntax::Syntax::Auto).unwrap();
+        let timetable = Timetable::from_parsed("0 0 1 * 1", parsed, DstPolicy::RunOnce);
+        // 2024-01-01 is a Monday.
+        let after = Utc.with_ymd_and_hms(2023, 12, 31, 0, 0, 0).unwrap();
+        let times: Vec<String> = Upcoming::after(&timetable, &after).take(7).map(|t| t.to_rfc3339()).collect();
+        assert_eq!(
+            times,
+            [
+                "2024-01-01T00:00:00+00:00",
+                "2024-01-08T00:00:00+00:00",
+                "2024-01-15T00:00:00+00:00",
+                "2024-01-22T00:00:00+00:00",
+                "2024-01-29T00:00:00+00:00",
+                "2024-02-01T00:00:00+00:00",
+                "2024-02-05T00:00:00+00:00",
+            ],
+        );
+        assert_eq!(timetable.to_string(), "0 0 1 * 1");
+        assert_eq!(timetable.trigger(&Utc.with_ymd_and_hms(2024, 1, 8, 0, 0, 0).unwrap()), None);
+    }
+
+    #[test]
+    fn any_merges_without_duplicates() {
+        let cron = |expr: &str| {
+            let parsed = crate::syntax::parse(expr, crate::syntax::Syntax::Auto).unwrap();
+            Timetable::from_parsed(expr, parsed, DstPolicy::RunOnce)
+        };
+        let timetable = Timetable::Any(vec![cron("0 9 * * 1-5"), cron("0 0 12 * * Sat"), cron("0 9 * * Fri")]);
+        // 2024-05-03 is a Friday.
+        let after = Berlin.with_ymd_and_hms(2024, 5, 3, 0, 0, 0).unwrap();
+        let times: Vec<_> = Upcoming::after(&timetable, &after).take(3).collect();
+        assert_eq!(
+            times.iter().map(|t| t.to_rfc3339()).collect::<Vec<_>>(),
+            ["2024-05-03T09:00:00+02:00", "2024-05-04T12:00:00+02:00", "2024-05-06T09:00:00+02:00"],
+        );
+        // Expressions are named as given, not in their seconds-first form.
+        assert_eq!(timetable.trigger(&times[0]).unwrap(), "0 9 * * 1-5, 0 9 * * Fri");
+        assert_eq!(timetable.trigger(&times[1]).unwrap(), "0 0 12 * * Sat");
+        assert_eq!(Timetable::Any(vec![cron("@daily"), cron(" */30 * * * * ")]).to_string(), "@daily, */30 * * * *");
+    }
+}
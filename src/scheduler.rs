@@ -0,0 +1,1031 @@
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info, log, warn, Level};
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, RandomState};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process::{Command as ProcessCommand, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::except::Exclusions;
+use crate::exit::ExitCode;
+use crate::runner::{self, Finished, Limits, Outcome, SPAWN_FAILED_CODE, TIMED_OUT_CODE};
+use crate::schedule::{Timetable, Upcoming};
+use crate::state::{JobState, StateFile};
+use crate::Cli;
+
+/// What to do when a run is due while the previous one is still active
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OverlapPolicy {
+    /// Drop the new run
+    Skip,
+    /// Start the new run once the active one has finished
+    Queue,
+    /// Start the new run alongside the active one
+    Parallel,
+    /// Stop the active run, then start the new one
+    Replace,
+}
+
+/// Which runs to make up for after croncycle woke up late
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CatchUp {
+    /// Skip all missed runs
+    None,
+    /// Run once for all missed runs together
+    Once,
+    /// Run once for every missed run, up to --catch-up-limit
+    All,
+}
+
+/// Exit code when the schedule has no more runs
+pub const EXHAUSTED_CODE: i32 = 3;
+
+/// How late a run may start before its fire time is considered missed
+const LATE_TOLERANCE: TimeDelta = TimeDelta::seconds(1);
+
+/// How often the spinner is animated while it is shown
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest time to sleep without looking at the wall clock, which can change
+/// or keep running during suspend without the sleep noticing
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of missed fire times logged at the start and at the end of a catch-up
+const MISSED_LOG_LINES: usize = 5;
+
+/// What the scheduler waits for besides the clock
+enum Event {
+    Finished(Finished),
+    /// croncycle received SIGINT or SIGTERM
+    Shutdown(i32),
+}
+
+struct Running {
+    id: u64,
+    pid: u32,
+    /// Scheduled time this run is for
+    slot: DateTime<Utc>,
+    /// Number of the attempt at this slot, starting at 1
+    attempt: u32,
+    replaced: bool,
+}
+
+/// A failed run waiting to be tried again
+struct Retry {
+    at: DateTime<Utc>,
+    slot: DateTime<Utc>,
+    attempt: u32,
+}
+
+pub struct Scheduler<'a> {
+    cli: &'a Cli,
+    spinner: ProgressBar,
+    tx: Sender<Event>,
+    rx: Receiver<Event>,
+    state: Option<StateFile>,
+    running: Vec<Running>,
+    queued: VecDeque<DateTime<Utc>>,
+    retries: Vec<Retry>,
+    /// Next scheduled fire time, which retries must not run into
+    next_fire: Option<DateTime<Utc>>,
+    next_id: u64,
+    /// Number of runs started so far
+    runs: usize,
+    /// Number of runs that succeeded, counting retries as one
+    successes: usize,
+    /// Number of runs that failed, counting retries as one
+    failed_runs: usize,
+    /// Number of runs that failed in a row
+    failures: u32,
+    /// Whether croncycle is stopping after SIGINT or SIGTERM
+    shutting_down: bool,
+    /// End of the pause after --pause-after-failures
+    paused_until: Option<DateTime<Utc>>,
+}
+
+impl<'a> Scheduler<'a> {
+    pub fn new(cli: &'a Cli, state: Option<StateFile>) -> Self {
+        let spinner = ProgressBar::new_spinner();
+        spinner.set_style(ProgressStyle::default_spinner()
+            .tick_strings(&["⠁", "⠂", "⠄", "⡀", "⢀", "⠠", "⠐", "⠈"])
+            .template("{spinner:.green} {msg}").expect("Failed to set spinner style"));
+
+        let (tx, rx) = mpsc::channel();
+
+        let shutdown_tx = tx.clone();
+        let handled = runner::on_shutdown_signal(move |signal| {
+            let _ = shutdown_tx.send(Event::Shutdown(signal));
+        });
+        if let Err(e) = handled {
+            warn!("Failed to handle shutdown signals, active runs will not be stopped gracefully: {}", e);
+        }
+
+        Scheduler {
+            cli,
+            spinner,
+            tx,
+            rx,
+            state,
+            running: Vec::new(),
+            queued: VecDeque::new(),
+            retries: Vec::new(),
+            next_fire: None,
+            next_id: 0,
+            runs: 0,
+            successes: 0,
+            failed_runs: 0,
+            failures: 0,
+            shutting_down: false,
+            paused_until: None,
+        }
+    }
+
+    pub fn run<Z: TimeZone>(&mut self, timetable: &Timetable, exclusions: &Exclusions, tz: Z) -> !
+    where
+        Z::Offset: Display,
+    {
+        info!("Scheduling {} in time zone {}", timetable, tz.from_utc_datetime(&Utc::now().naive_utc()).offset());
+
+        // Resume after the last run we know of, so that runs missed while
+        // croncycle was not running are caught up on like any other.
+        let state = self.load_state();
+        let mut after = match state.last_attempted {
+            Some(last_attempted) => {
+                info!("Last run was scheduled at {:?}", last_attempted.with_timezone(&tz));
+                last_attempted.with_timezone(&tz)
+            },
+            None => Utc::now().with_timezone(&tz),
+        };
+
+        if let (Some(jitter), Some(seed)) = (self.cli.jitter, &self.cli.splay_seed) {
+            let delay = self.jitter(&after).to_std().unwrap_or_default();
+            info!(
+                "Delaying runs by {} of up to {} for --splay-seed {}",
+                humantime::format_duration(delay), humantime::format_duration(jitter), seed,
+            );
+        }
+
+        let until = self.cli.schedule.until.as_ref().map(|until| until.resolve(&tz));
+        let not_before = self.cli.schedule.not_before.as_ref().map(|not_before| not_before.resolve(&tz));
+        if let Some(not_before) = &not_before {
+            if after < *not_before {
+                info!("Not running before {:?}", not_before);
+                // Fire times are strictly after `after`, so back off to include `not_before` itself.
+                after = not_before.clone() - TimeDelta::milliseconds(1);
+            }
+        }
+
+        let now = Utc::now().with_timezone(&tz);
+        let run_at_start = if timetable.runs_at_startup() {
+            true
+        } else if self.cli.run_at_start {
+            info!("Running at startup for --run-at-start");
+            true
+        } else if self.cli.run_at_start_if_overdue {
+            // Overdue when a fire time has passed since the last successful run.
+            let last_success = state.last_success.map(|t| t.with_timezone(&tz));
+            let due = match &last_success {
+                Some(last_success) => Upcoming::after(timetable, last_success).next().filter(|t| *t <= now),
+                None => Some(now.clone()),
+            };
+            match (due, last_success) {
+                (Some(due), Some(last_success)) => {
+                    info!("Run due at {:?} is overdue, last successful run was scheduled at {:?}, running at startup", due, last_success);
+                    true
+                },
+                (Some(_), None) => {
+                    info!("No successful run recorded, running at startup");
+                    true
+                },
+                (None, _) => false,
+            }
+        } else {
+            false
+        };
+
+        if run_at_start {
+            if let Some(reason) = exclusions.reason(&now) {
+                info!("Skipping run at startup, {}", reason);
+            } else if not_before.as_ref().is_some_and(|not_before| now < *not_before) {
+                info!("Skipping run at startup, it is before --not-before");
+            } else {
+                // The run at startup makes up for any runs missed before it.
+                if after < now {
+                    after = now.clone();
+                }
+                self.next_fire = next_fire_after(timetable, &after);
+                self.fire(now.with_timezone(&Utc));
+            }
+        }
+
+        loop {
+            if self.runs_left() == 0 {
+                self.finish(&format!("Reached --max-runs {}", self.runs));
+            }
+
+            let Some(next_run) = Upcoming::after(timetable, &after).next() else {
+                self.next_fire = None;
+                // Running only at startup is what @reboot is for, not a schedule running out.
+                if let Timetable::Reboot = timetable {
+                    self.finish("@reboot has no runs after startup");
+                }
+                self.spinner.set_message("Schedule has no more runs".to_string());
+                info!("Schedule has no more runs after {:?}", after);
+                self.drain();
+                info!("Finished after {} run(s)", self.runs);
+                std::process::exit(EXHAUSTED_CODE);
+            };
+
+            if let Some(until) = &until {
+                if next_run > *until {
+                    self.finish(&format!("Next run at {:?} is after --until {:?}", next_run, until));
+                }
+            }
+
+            if next_run <= after {
+                // Cannot happen with a working schedule, but make sure we move on.
+                error!("Schedule returned {:?}, which is not after {:?}, skipping it", next_run, after);
+                after += TimeDelta::seconds(1);
+                continue;
+            }
+
+            self.next_fire = Some(next_run.with_timezone(&Utc));
+            let trigger = timetable.trigger(&next_run).map(|t| format!(" for {}", t)).unwrap_or_default();
+            let excluded = exclusions.reason(&next_run);
+            let run_at = match excluded {
+                Some(_) => next_run.clone(),
+                None => next_run.clone() + self.jitter(&next_run),
+            };
+            match &excluded {
+                Some(reason) => self.wait_until(&next_run, &format!("{} (skipped, {})", trigger, reason)),
+                None if run_at != next_run => {
+                    let delay = humantime::format_duration((run_at.clone() - next_run.clone()).to_std().unwrap_or_default());
+                    self.wait_until(&run_at, &format!("{} ({:?} delayed by {})", trigger, next_run, delay));
+                },
+                None => self.wait_until(&next_run, &trigger),
+            }
+
+            let now = Utc::now();
+            let late = now.signed_duration_since(&run_at);
+            if late <= LATE_TOLERANCE {
+                if let Some(reason) = excluded {
+                    info!("Skipping run at {:?}{}, {}", next_run, trigger, reason);
+                } else if self.paused() {
+                    info!("Skipping run at {:?}{}, paused after consecutive failures", next_run, trigger);
+                } else {
+                    if !trigger.is_empty() {
+                        info!("Running{}", trigger);
+                    }
+                    // Retries of this run must stop before the one after it.
+                    self.next_fire = next_fire_after(timetable, &next_run);
+                    self.fire(next_run.with_timezone(&Utc));
+                }
+                after = next_run;
+                continue;
+            }
+
+            warn!(
+                "Fell {} behind schedule, croncycle was not running, the system was suspended or the clock changed",
+                humantime::format_duration(Duration::from_secs(late.num_seconds() as u64)),
+            );
+
+            let missed = missed(timetable, exclusions, next_run, &now.with_timezone(&tz), until.as_ref(), self.cli.catch_up_limit);
+            after = missed.last;
+
+            if missed.count > 0 && self.paused() {
+                info!("Not catching up on {} missed run(s), paused after consecutive failures", missed.count);
+            } else if missed.count > 0 {
+                self.next_fire = next_fire_after(timetable, &after);
+                self.catch_up(missed.count, missed.slots);
+            }
+        }
+    }
+
+    /// Sleeps until the wall clock reaches `deadline`, handling finished runs in the meantime.
+    /// `trigger` is appended to the spinner message to say what the next run is for.
+    ///
+    /// Only wakes up early to animate the spinner when it is shown, and to
+    /// re-check the wall clock in case it changed or the system was suspended.
+    fn wait_until<Z: TimeZone>(&mut self, deadline: &DateTime<Z>, trigger: &str)
+    where
+        Z::Offset: Display,
+    {
+        loop {
+            self.start_due_retries();
+            let remaining = match deadline.with_timezone(&Utc).signed_duration_since(Utc::now()).to_std() {
+                Ok(remaining) if !remaining.is_zero() => remaining.min(self.until_next_retry().unwrap_or(remaining)),
+                _ => return,
+            };
+
+            if self.running.is_empty() {
+                self.spinner.set_message(format!("Next run at {:?}{}", deadline, trigger));
+            } else {
+                self.spinner.set_message(format!("Running job... next run at {:?}{}", deadline, trigger));
+            }
+            self.spinner.tick();
+
+            let interval = if self.spinner.is_hidden() { CLOCK_CHECK_INTERVAL } else { SPINNER_INTERVAL };
+            match self.rx.recv_timeout(remaining.min(interval)) {
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => unreachable!("scheduler holds a sender"),
+            }
+        }
+    }
+
+    fn catch_up(&mut self, missed: usize, slots: VecDeque<DateTime<Utc>>) {
+        let kept = slots.len();
+        match self.cli.catch_up {
+            CatchUp::None => info!("Not catching up on {} missed run(s)", missed),
+            CatchUp::Once => info!("Catching up on {} missed run(s) with a single run", missed),
+            CatchUp::All if kept < missed => {
+                warn!("Catching up on only the last {} of {} missed runs due to --catch-up-limit", kept, missed);
+            },
+            CatchUp::All => info!("Catching up on {} missed run(s)", missed),
+        }
+
+        // Missed runs go one after another, whatever the overlap policy.
+        let mut runs = catch_up_runs(self.cli.catch_up, slots);
+        if let Some(first) = runs.pop_front() {
+            self.fire(first);
+            let left = self.runs_left();
+            self.queued.extend(runs.into_iter().take(left));
+        }
+    }
+
+    fn fire(&mut self, slot: DateTime<Utc>) {
+        if self.running.is_empty() {
+            self.start(slot, 1);
+            return;
+        }
+
+        let limit = self.cli.overlap_limit;
+        match self.cli.overlap {
+            OverlapPolicy::Skip => warn!("Previous run is still active, skipping this run"),
+            OverlapPolicy::Queue if self.queued.len() < limit => {
+                self.queued.push_back(slot);
+                info!("Previous run is still active, queueing this run ({} queued)", self.queued.len());
+            },
+            // The limit counts runs besides the first active one, like queued runs.
+            OverlapPolicy::Parallel if self.running.len() <= limit => self.start(slot, 1),
+            OverlapPolicy::Queue | OverlapPolicy::Parallel => {
+                warn!("Overlap limit of {} reached, skipping this run", limit);
+            },
+            OverlapPolicy::Replace => {
+                self.replace();
+                self.start(slot, 1);
+            },
+        }
+    }
+
+    fn start(&mut self, slot: DateTime<Utc>, attempt: u32) {
+        if attempt == 1 {
+            self.runs += 1;
+        }
+        if self.cli.retries > 0 {
+            info!("Starting attempt {} of {}", attempt, self.cli.retries + 1);
+        }
+        self.spinner.set_message("Running job...".to_string());
+        self.save_state(|state| state.last_attempted = Some(slot));
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let limits = Limits { timeout: self.cli.timeout, kill_after: self.cli.kill_after, group: self.process_groups() };
+        let tx = self.tx.clone();
+        let notify = move |finished| {
+            // The receiver only goes away when croncycle is exiting anyway.
+            let _ = tx.send(Event::Finished(finished));
+        };
+        match runner::spawn(&mut self.command(), id, limits, &self.cli.stderr_prefix, notify) {
+            Ok(pid) => self.running.push(Running { id, pid, slot, attempt, replaced: false }),
+            Err(e) => {
+                error!("Failed to execute command: {}", e);
+                self.spinner.set_message(format!("Error: Failed to execute command: {}", e));
+                self.failed(slot, attempt, ExitCode::Code(SPAWN_FAILED_CODE));
+            },
+        }
+    }
+
+    fn command(&self) -> ProcessCommand {
+        let cli = self.cli;
+        let mut command_proc = if cli.shell.is_some() || cli.shell_login {
+            let shell = match &cli.shell {
+                Some(Some(shell)) => shell.clone(),
+                _ => std::env::var_os("SHELL").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/bin/sh")),
+            };
+            let mut command_proc = ProcessCommand::new(shell);
+            if cli.shell_login {
+                command_proc.arg("-l");
+            }
+            command_proc.arg("-c").arg(runner::shell_command(&cli.command));
+            command_proc
+        } else {
+            let mut command_proc = ProcessCommand::new(&cli.command[0]);
+            command_proc.args(&cli.command[1..]);
+            command_proc
+        };
+
+        // Commands get a process group of their own, so that Ctrl-C in a terminal
+        // only reaches croncycle, which forwards it. With stdin enabled they stay
+        // in the foreground group to be able to read from the terminal.
+        #[cfg(unix)]
+        if self.process_groups() {
+            use std::os::unix::process::CommandExt;
+            command_proc.process_group(0);
+        }
+
+        if cli.enable_stdin {
+            command_proc.stdin(Stdio::inherit());
+        } else {
+            command_proc.stdin(Stdio::null());
+        }
+
+        if cli.no_output {
+            command_proc.stdout(Stdio::null());
+        } else {
+            command_proc.stdout(Stdio::inherit());
+        }
+
+        if cli.stderr_to_stdout && cli.no_output {
+            command_proc.stderr(Stdio::null());
+        } else if cli.stderr_to_stdout {
+            match runner::stdout() {
+                Ok(stdout) => command_proc.stderr(stdout),
+                Err(e) => {
+                    warn!("Failed to redirect command stderr to stdout: {}", e);
+                    command_proc.stderr(Stdio::inherit())
+                },
+            };
+        } else if cli.inherit_stderr {
+            command_proc.stderr(Stdio::inherit());
+        } else {
+            command_proc.stderr(Stdio::piped());
+        }
+
+        command_proc
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Finished(finished) => self.finished(finished),
+            Event::Shutdown(signal) => self.shutdown(signal),
+        }
+    }
+
+    fn finished(&mut self, finished: Finished) {
+        let job = match self.running.iter().position(|job| job.id == finished.id) {
+            Some(index) => self.running.remove(index),
+            None => return,
+        };
+
+        if job.replaced {
+            info!("Replaced run has stopped");
+        } else {
+            match finished.result {
+                Ok(Outcome::Exited(status)) if status.success() => {
+                    info!("Command exited with status {}", status);
+                    self.succeeded(&job);
+                },
+                Ok(Outcome::Exited(status)) if self.is_success(ExitCode::of(&status)) => {
+                    info!("Command exited with {}, counted as success by --success-codes", ExitCode::of(&status));
+                    self.succeeded(&job);
+                },
+                Ok(Outcome::Exited(status)) => {
+                    let code = ExitCode::of(&status);
+                    if let ExitCode::Signal(_) = code {
+                        error!("Command was killed by {}", code);
+                        self.spinner.set_message(format!("Error: Command was killed by {}", code));
+                    } else {
+                        self.spinner.set_message(format!("Error: Command exited with status {}", status));
+                    }
+                    self.failed(job.slot, job.attempt, code);
+                },
+                Ok(Outcome::TimedOut) if self.is_success(ExitCode::Code(TIMED_OUT_CODE)) => {
+                    info!("Command timed out, counted as success by --success-codes");
+                    self.succeeded(&job);
+                },
+                Ok(Outcome::TimedOut) => {
+                    error!("Command timed out");
+                    self.spinner.set_message("Error: Command timed out".to_string());
+                    self.failed(job.slot, job.attempt, ExitCode::Code(TIMED_OUT_CODE));
+                },
+                Err(e) => error!("Failed to wait for command: {}", e),
+            }
+        }
+
+        if self.running.is_empty() {
+            if let Some(slot) = self.queued.pop_front() {
+                self.start(slot, 1);
+            }
+        }
+    }
+
+    fn is_success(&self, code: ExitCode) -> bool {
+        self.cli.success_codes.iter().any(|pattern| pattern.matches(code))
+    }
+
+    fn succeeded(&mut self, job: &Running) {
+        self.successes += 1;
+        self.failures = 0;
+        self.save_state(|state| state.last_success = Some(job.slot));
+    }
+
+    /// Handles attempt number `attempt` at `slot` failing with `code`: retries
+    /// it, or counts it towards --exit-on-error and the limits on consecutive failures.
+    fn failed(&mut self, slot: DateTime<Utc>, attempt: u32, code: ExitCode) {
+        let cli = self.cli;
+        // Only the last attempt counts, and runs stopped by a shutdown are not retried.
+        if !self.shutting_down && self.retry(slot, attempt) {
+            return;
+        }
+        self.failed_runs += 1;
+        if self.shutting_down || cli.ignored_codes.iter().any(|pattern| pattern.matches(code)) {
+            return;
+        }
+        if cli.exit_on_error {
+            std::process::exit(code.status());
+        }
+
+        self.failures += 1;
+        if cli.max_consecutive_failures.is_some_and(|max| self.failures >= max) {
+            error!("{} consecutive failures, exiting", self.failures);
+            std::process::exit(code.status());
+        }
+        if let (Some(after), Some(pause_for)) = (cli.pause_after_failures, cli.pause_for) {
+            if self.failures.is_multiple_of(after) {
+                let until = Utc::now() + TimeDelta::from_std(pause_for).unwrap_or(TimeDelta::MAX);
+                warn!("{} consecutive failures, pausing until {:?}", self.failures, until);
+                self.paused_until = Some(until);
+            }
+        }
+    }
+
+    /// Whether runs are paused after consecutive failures, ending the pause once it is over.
+    fn paused(&mut self) -> bool {
+        match self.paused_until {
+            Some(until) if Utc::now() < until => true,
+            Some(_) => {
+                info!("Pause after consecutive failures is over, resuming");
+                self.paused_until = None;
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Schedules another attempt after attempt number `attempt` at `slot` failed,
+    /// returning whether there will be one.
+    fn retry(&mut self, slot: DateTime<Utc>, attempt: u32) -> bool {
+        let cli = self.cli;
+        if attempt > cli.retries {
+            if cli.retries > 0 {
+                error!("Attempt {} of {} failed, giving up", attempt, cli.retries + 1);
+            }
+            return false;
+        }
+
+        let delay = retry_delay(cli.retry_delay, cli.retry_backoff, cli.retry_max_delay, attempt);
+        let Some(at) = retry_at(Utc::now(), delay, self.next_fire) else {
+            let next_fire = self.next_fire.expect("retries are only cut off by the next fire time");
+            warn!("Attempt {} of {} failed, not retrying as the next run is due at {:?}", attempt, cli.retries + 1, next_fire);
+            return false;
+        };
+
+        warn!("Attempt {} of {} failed, retrying in {}", attempt, cli.retries + 1, humantime::format_duration(delay));
+        self.retries.push(Retry { at, slot, attempt: attempt + 1 });
+        true
+    }
+
+    /// Starts the retries that are due.
+    fn start_due_retries(&mut self) {
+        let now = Utc::now();
+        while let Some(index) = self.retries.iter().position(|retry| retry.at <= now) {
+            let retry = self.retries.remove(index);
+            self.start(retry.slot, retry.attempt);
+        }
+    }
+
+    fn until_next_retry(&self) -> Option<Duration> {
+        let at = self.retries.iter().map(|retry| retry.at).min()?;
+        Some(at.signed_duration_since(Utc::now()).to_std().unwrap_or_default())
+    }
+
+    fn load_state(&self) -> JobState {
+        let Some(state) = &self.state else {
+            return JobState::default();
+        };
+        state.load().unwrap_or_else(|e| {
+            error!("Failed to read state file: {}", e);
+            JobState::default()
+        })
+    }
+
+    fn save_state(&self, f: impl FnOnce(&mut JobState)) {
+        if let Some(state) = &self.state {
+            if let Err(e) = state.update(f) {
+                error!("Failed to write state file: {}", e);
+            }
+        }
+    }
+
+    /// Stops all active runs, escalating to SIGKILL after --kill-after, and
+    /// drops their pending retries.
+    fn replace(&mut self) {
+        warn!("Previous run is still active, replacing it");
+        self.retries.clear();
+        let group = self.process_groups();
+        let pids: Vec<u32> = self.running.iter().map(|job| job.pid).collect();
+        for job in &mut self.running {
+            job.replaced = true;
+            if let Err(e) = runner::terminate(job.pid, group) {
+                error!("Failed to terminate command: {}", e);
+            }
+        }
+
+        let deadline = Instant::now() + self.cli.kill_after;
+        while !self.running.is_empty() {
+            match self.rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => unreachable!("scheduler holds a sender"),
+            }
+        }
+
+        for job in &self.running {
+            warn!("Replaced run still active after {}, killing", humantime::format_duration(self.cli.kill_after));
+            if let Err(e) = runner::kill(job.pid, group) {
+                error!("Failed to kill command: {}", e);
+            }
+        }
+
+        // Processes a replaced run started may ignore SIGTERM and outlive it.
+        if group {
+            for pid in pids.into_iter().filter(|pid| !self.running.iter().any(|job| job.pid == *pid)) {
+                if !runner::wait_group(pid, deadline) {
+                    warn!("Processes started by a replaced run still active after {}, killing", humantime::format_duration(self.cli.kill_after));
+                    if let Err(e) = runner::kill(pid, true) {
+                        error!("Failed to kill command: {}", e);
+                    }
+                }
+            }
+        }
+
+        self.drain();
+    }
+
+    /// Delay of the run at `slot` within --jitter, which is the same for every
+    /// run with --splay-seed.
+    fn jitter<Z: TimeZone>(&self, slot: &DateTime<Z>) -> TimeDelta {
+        match self.cli.jitter {
+            Some(jitter) => jitter_delay(jitter, self.cli.splay_seed.as_deref(), slot),
+            None => TimeDelta::zero(),
+        }
+    }
+
+    /// Number of runs --max-runs still allows, counting queued runs as started.
+    fn runs_left(&self) -> usize {
+        match self.cli.max_runs {
+            Some(max_runs) => max_runs.saturating_sub(self.runs + self.queued.len()),
+            None => usize::MAX,
+        }
+    }
+
+    /// Waits for all active and queued runs to finish, then exits with status 0.
+    fn finish(&mut self, reason: &str) -> ! {
+        info!("{}, finishing", reason);
+        self.drain();
+        info!("Finished after {} run(s)", self.runs);
+        std::process::exit(0);
+    }
+
+    /// Forwards the shutdown signal to active runs, waits up to --shutdown-grace
+    /// for them to stop and exits with 128 plus the signal number.
+    fn shutdown(&mut self, signal: i32) -> ! {
+        let code = ExitCode::Signal(signal);
+        warn!("Received {}, shutting down", code);
+        self.shutting_down = true;
+        self.queued.clear();
+        self.retries.clear();
+
+        // Commands reading the terminal share its process group and already got Ctrl-C.
+        #[cfg(unix)]
+        let forward = !(self.cli.enable_stdin && signal == libc::SIGINT);
+        #[cfg(not(unix))]
+        let forward = true;
+        let group = self.process_groups();
+        let pids: Vec<u32> = self.running.iter().map(|job| job.pid).collect();
+        if forward {
+            for job in &self.running {
+                if let Err(e) = runner::signal(job.pid, group, signal) {
+                    error!("Failed to forward {} to command: {}", code, e);
+                }
+            }
+        }
+
+        let mut deadline = Instant::now() + self.cli.shutdown_grace;
+        while !self.running.is_empty() {
+            self.spinner.set_message("Waiting for active runs to stop...".to_string());
+            match self.rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Event::Finished(finished)) => self.finished(finished),
+                Ok(Event::Shutdown(_)) => {
+                    warn!("Received another signal, not waiting any longer");
+                    deadline = Instant::now();
+                    break;
+                },
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => unreachable!("scheduler holds a sender"),
+            }
+        }
+
+        for job in &self.running {
+            warn!("Run still active after --shutdown-grace {}, killing", humantime::format_duration(self.cli.shutdown_grace));
+            if let Err(e) = runner::kill(job.pid, group) {
+                error!("Failed to kill command: {}", e);
+            }
+        }
+
+        // Processes a run started may ignore the signal and outlive it.
+        if group {
+            for pid in pids.into_iter().filter(|pid| !self.running.iter().any(|job| job.pid == *pid)) {
+                if !runner::wait_group(pid, deadline) {
+                    warn!("Processes started by a run still active after --shutdown-grace {}, killing", humantime::format_duration(self.cli.shutdown_grace));
+                    if let Err(e) = runner::kill(pid, true) {
+                        error!("Failed to kill command: {}", e);
+                    }
+                }
+            }
+        }
+
+        self.spinner.finish_and_clear();
+        info!(
+            "Shut down after {} run(s): {} succeeded, {} failed, {} killed",
+            self.runs, self.successes, self.failed_runs, self.running.len(),
+        );
+        std::process::exit(code.status());
+    }
+
+    /// Whether runs get a process group of their own, so that signals reach any
+    /// processes they started. With --enable-stdin they share croncycle's.
+    fn process_groups(&self) -> bool {
+        !self.cli.enable_stdin
+    }
+
+    /// Waits for all active, queued and retried runs to finish.
+    fn drain(&mut self) {
+        loop {
+            self.start_due_retries();
+            if self.running.is_empty() && self.retries.is_empty() {
+                return;
+            }
+            self.spinner.set_message("Waiting for active runs to finish...".to_string());
+            match self.until_next_retry() {
+                Some(timeout) => match self.rx.recv_timeout(timeout) {
+                    Ok(event) => self.handle(event),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => unreachable!("scheduler holds a sender"),
+                },
+                None => {
+                    let event = self.rx.recv().expect("scheduler holds a sender");
+                    self.handle(event);
+                },
+            }
+        }
+    }
+}
+
+/// The first fire time of `timetable` after `t`.
+fn next_fire_after<Z: TimeZone>(timetable: &Timetable, t: &DateTime<Z>) -> Option<DateTime<Utc>> {
+    Upcoming::after(timetable, t).next().map(|next| next.with_timezone(&Utc))
+}
+
+/// Fire times missed while croncycle was not keeping up
+struct Missed<Z: TimeZone> {
+    /// Number of missed runs, not counting excluded fire times
+    count: usize,
+    /// The most recent missed runs, up to --catch-up-limit
+    slots: VecDeque<DateTime<Utc>>,
+    /// Last fire time looked at, which scheduling resumes after
+    last: DateTime<Z>,
+}
+
+/// Collects the fire times from `first` up to `now` and --until, keeping only
+/// the most recent `limit` of them.
+fn missed<Z: TimeZone>(
+    timetable: &Timetable,
+    exclusions: &Exclusions,
+    first: DateTime<Z>,
+    now: &DateTime<Z>,
+    until: Option<&DateTime<Z>>,
+    limit: usize,
+) -> Missed<Z> {
+    let mut missed = Missed { count: 0, slots: VecDeque::new(), last: first.clone() };
+    let mut log = CappedLog::default();
+    for slot in std::iter::once(first.clone()).chain(Upcoming::after(timetable, &first)) {
+        if slot > *now || until.is_some_and(|until| slot > *until) {
+            break;
+        }
+        let trigger = timetable.trigger(&slot).map(|t| format!(" for {}", t)).unwrap_or_default();
+        missed.last = slot.clone();
+        if let Some(reason) = exclusions.reason(&slot) {
+            log.push(Level::Info, format!("Skipping missed run at {:?}{}, {}", slot, trigger, reason));
+            continue;
+        }
+        log.push(Level::Warn, format!("Missed run at {:?}{}", slot, trigger));
+        missed.count += 1;
+        missed.slots.push_back(slot.with_timezone(&Utc));
+        if missed.slots.len() > limit {
+            missed.slots.pop_front();
+        }
+    }
+    log.flush();
+    missed
+}
+
+/// Runs to start for the missed runs in `slots` under a --catch-up policy.
+fn catch_up_runs(policy: CatchUp, mut slots: VecDeque<DateTime<Utc>>) -> VecDeque<DateTime<Utc>> {
+    match policy {
+        CatchUp::None => VecDeque::new(),
+        CatchUp::Once => slots.pop_back().into_iter().collect(),
+        CatchUp::All => slots,
+    }
+}
+
+/// Logs only the first and last few of a long series of lines, so that a job
+/// resumed after weeks does not log every fire time it missed.
+#[derive(Default)]
+struct CappedLog {
+    logged: usize,
+    tail: VecDeque<(Level, String)>,
+    omitted: usize,
+}
+
+impl CappedLog {
+    fn push(&mut self, level: Level, line: String) {
+        if self.logged < MISSED_LOG_LINES {
+            log!(level, "{}", line);
+            self.logged += 1;
+            return;
+        }
+        self.tail.push_back((level, line));
+        if self.tail.len() > MISSED_LOG_LINES {
+            self.tail.pop_front();
+            self.omitted += 1;
+        }
+    }
+
+    fn flush(self) {
+        if self.omitted > 0 {
+            warn!("... {} more missed fire time(s) not listed ...", self.omitted);
+        }
+        for (level, line) in self.tail {
+            log!(level, "{}", line);
+        }
+    }
+}
+
+/// Delay before retrying after attempt number `attempt` failed, which grows by
+/// `backoff` with every attempt, up to `max`.
+fn retry_delay(delay: Duration, backoff: f64, max: Duration, attempt: u32) -> Duration {
+    let retries = i32::try_from(attempt.saturating_sub(1)).unwrap_or(i32::MAX);
+    let delay = delay.as_secs_f64() * backoff.powi(retries);
+    Duration::try_from_secs_f64(delay).unwrap_or(max).min(max)
+}
+
+/// When to retry after `delay` from `now`, `None` if the retry would run into
+/// the next fire time.
+fn retry_at(now: DateTime<Utc>, delay: Duration, next_fire: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    let at = TimeDelta::from_std(delay)
+        .ok()
+        .and_then(|delay| now.checked_add_signed(delay))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    match next_fire {
+        Some(next_fire) if at >= next_fire => None,
+        _ => Some(at),
+    }
+}
+
+/// Delay below `jitter` for the run at `slot`, derived from `seed` if given
+/// and random otherwise.
+fn jitter_delay<Z: TimeZone>(jitter: Duration, seed: Option<&str>, slot: &DateTime<Z>) -> TimeDelta {
+    if jitter.is_zero() {
+        return TimeDelta::zero();
+    }
+    let hash = match seed {
+        Some(seed) => fnv1a(seed.as_bytes()),
+        None => RandomState::new().hash_one(slot.timestamp_nanos_opt()),
+    };
+    let millis = jitter.as_millis().max(1) as u64;
+    TimeDelta::milliseconds((hash % millis) as i64)
+}
+
+/// 64-bit FNV-1a, which unlike the std hashers is stable across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::Anchor;
+
+    fn minutely() -> Timetable {
+        Timetable::Every { interval: TimeDelta::minutes(1), anchor: Anchor::WallClock }
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn keeps_most_recent_missed_runs() {
+        let now = at("2024-05-31T00:00:30Z");
+        let missed = missed(&minutely(), &Exclusions::default(), at("2024-05-01T00:00:00Z"), &now, None, 3);
+        assert_eq!(missed.count, 30 * 24 * 60 + 1);
+        assert_eq!(missed.slots, [at("2024-05-30T23:58:00Z"), at("2024-05-30T23:59:00Z"), at("2024-05-31T00:00:00Z")]);
+        assert_eq!(missed.last, at("2024-05-31T00:00:00Z"));
+    }
+
+    #[test]
+    fn stops_missed_runs_at_until() {
+        let now = at("2024-05-01T01:00:00Z");
+        let until = at("2024-05-01T00:04:30Z");
+        let missed = missed(&minutely(), &Exclusions::default(), at("2024-05-01T00:00:00Z"), &now, Some(&until), 10);
+        assert_eq!(missed.count, 5);
+        assert_eq!(missed.last, at("2024-05-01T00:04:00Z"));
+    }
+
+    #[test]
+    fn does_not_count_excluded_missed_runs() {
+        let mut exclusions = Exclusions::default();
+        exclusions.add_dates(PathBuf::from("holidays"), [at("2024-05-01T00:00:00Z").date_naive()].into());
+        let now = at("2024-05-02T00:01:00Z");
+        let missed = missed(&minutely(), &exclusions, at("2024-05-01T00:00:00Z"), &now, None, 10);
+        assert_eq!(missed.count, 2);
+        assert_eq!(missed.slots, [at("2024-05-02T00:00:00Z"), at("2024-05-02T00:01:00Z")]);
+        assert_eq!(missed.last, now);
+    }
+
+    #[test]
+    fn retry_delay_grows_up_to_max() {
+        let (delay, max) = (Duration::from_secs(10), Duration::from_secs(60));
+        let delays: Vec<_> = (1..=5).map(|attempt| retry_delay(delay, 2.0, max, attempt)).collect();
+        assert_eq!(delays, [10, 20, 40, 60, 60].map(Duration::from_secs));
+        assert_eq!(retry_delay(delay, 1.0, max, 5), delay);
+        assert_eq!(retry_delay(Duration::from_millis(1500), 1.5, max, 2), Duration::from_millis(2250));
+    }
+
+    #[test]
+    fn retry_delay_overflow_falls_back_to_max() {
+        let max = Duration::from_secs(3600);
+        assert_eq!(retry_delay(Duration::from_secs(10), 1e300, max, 3), max);
+        assert_eq!(retry_delay(Duration::from_secs(10), 2.0, max, u32::MAX), max);
+        assert_eq!(retry_delay(Duration::MAX, 2.0, Duration::MAX, 2), Duration::MAX);
+    }
+
+    #[test]
+    fn retries_stop_before_next_fire() {
+        let now = at("2024-05-01T00:00:00Z");
+        let next_fire = at("2024-05-01T00:01:00Z");
+        assert_eq!(retry_at(now, Duration::from_secs(30), Some(next_fire)), Some(at("2024-05-01T00:00:30Z")));
+        assert_eq!(retry_at(now, Duration::from_secs(60), Some(next_fire)), None);
+        assert_eq!(retry_at(now, Duration::from_secs(90), None), Some(at("2024-05-01T00:01:30Z")));
+        assert_eq!(retry_at(now, Duration::MAX, Some(next_fire)), None);
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        // Test vectors from the FNV reference implementation.
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn seeded_jitter_is_stable_and_below_limit() {
+        let jitter = Duration::from_secs(300);
+        let (first, second) = (at("2024-05-01T00:00:00Z"), at("2024-05-01T01:00:00Z"));
+        // Hosts seeded with the same name must keep their offset across releases.
+        assert_eq!(jitter_delay(jitter, Some("web-1"), &first), TimeDelta::milliseconds(249_439));
+        for n in 0..1000 {
+            let seed = format!("web-{}", n);
+            let delay = jitter_delay(jitter, Some(&seed), &first);
+            assert!(delay >= TimeDelta::zero() && delay < TimeDelta::from_std(jitter).unwrap());
+            assert_eq!(delay, jitter_delay(jitter, Some(&seed), &second));
+        }
+        assert_eq!(jitter_delay(Duration::ZERO, Some("web-1"), &first), TimeDelta::zero());
+        assert!(jitter_delay(Duration::from_millis(1), None, &first).is_zero());
+    }
+
+    #[test]
+    fn picks_catch_up_runs() {
+        let slots = VecDeque::from([at("2024-05-01T00:00:00Z"), at("2024-05-01T00:01:00Z"), at("2024-05-01T00:02:00Z")]);
+        assert!(catch_up_runs(CatchUp::None, slots.clone()).is_empty());
+        assert_eq!(catch_up_runs(CatchUp::Once, slots.clone()), [at("2024-05-01T00:02:00Z")]);
+        assert_eq!(catch_up_runs(CatchUp::All, slots.clone()), slots);
+    }
+}
@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What croncycle remembers about a job between restarts
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JobState {
+    /// Scheduled time of the last run that was started
+    pub last_attempted: Option<DateTime<Utc>>,
+    /// Scheduled time of the last run that succeeded
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+/// A JSON file holding the [`JobState`] of any number of jobs, keyed by job name.
+///
+/// Updates rewrite the whole file through a temporary file so that a crash
+/// never leaves it half written, holding a lock on `<path>.lock` so that jobs
+/// sharing the file do not lose each other's entries.
+pub struct StateFile {
+    path: PathBuf,
+    key: String,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf, key: String) -> Self {
+        StateFile { path, key }
+    }
+
+    /// Loads the state of this job, which is empty if the file does not exist yet.
+    pub fn load(&self) -> io::Result<JobState> {
+        Ok(self.read()?.remove(&self.key).unwrap_or_default())
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut JobState)) -> io::Result<()> {
+        // The lock is released when the file is closed.
+        let lock = File::create(self.with_suffix(".lock"))?;
+        lock.lock()?;
+
+        let mut jobs = self.read()?;
+        f(jobs.entry(self.key.clone()).or_default());
+
+        // Each update writes a temporary file of its own, so that writers never
+        // clobber each other's even without the lock.
+        static UPDATES: AtomicU64 = AtomicU64::new(0);
+        let tmp = self.with_suffix(&format!(".{}.{}.tmp", process::id(), UPDATES.fetch_add(1, Ordering::Relaxed)));
+        let result = fs::write(&tmp, serde_json::to_vec_pretty(&jobs)?).and_then(|_| fs::rename(&tmp, &self.path));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn with_suffix(&self, suffix: &str) -> OsString {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        path
+    }
+
+    fn read(&self) -> io::Result<BTreeMap<String, JobState>> {
+        match fs::read(&self.path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("croncycle-{}-{}.json", name, process::id()));
+        remove(&path);
+        path
+    }
+
+    fn remove(path: &PathBuf) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(StateFile::new(path.clone(), String::new()).with_suffix(".lock"));
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn round_trips_job_state() {
+        let state = StateFile::new(path("round-trip"), "backup".to_string());
+        assert!(state.load().unwrap().last_attempted.is_none());
+
+        state.update(|job| job.last_attempted = Some(at("2024-05-01T03:00:00Z"))).unwrap();
+        state.update(|job| job.last_success = Some(at("2024-05-01T03:00:00Z"))).unwrap();
+        let job = state.load().unwrap();
+        assert_eq!(job.last_attempted, Some(at("2024-05-01T03:00:00Z")));
+        assert_eq!(job.last_success, Some(at("2024-05-01T03:00:00Z")));
+        remove(&state.path);
+    }
+
+    #[test]
+    fn keeps_other_jobs() {
+        let path = path("two-keys");
+        let backup = StateFile::new(path.clone(), "backup".to_string());
+        let report = StateFile::new(path.clone(), "report".to_string());
+        backup.update(|job| job.last_attempted = Some(at("2024-05-01T03:00:00Z"))).unwrap();
+        report.update(|job| job.last_attempted = Some(at("2024-05-01T09:00:00Z"))).unwrap();
+        assert_eq!(backup.load().unwrap().last_attempted, Some(at("2024-05-01T03:00:00Z")));
+        assert_eq!(report.load().unwrap().last_attempted, Some(at("2024-05-01T09:00:00Z")));
+        remove(&path);
+    }
+
+    #[test]
+    fn concurrent_updates_keep_every_job() {
+        let path = path("concurrent");
+        let writers: Vec<_> = (0..8)
+            .map(|n| {
+                let state = StateFile::new(path.clone(), format!("job-{}", n));
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        state.update(|job| job.last_attempted = Some(Utc::now())).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let jobs = StateFile::new(path.clone(), String::new()).read().unwrap();
+        assert_eq!(jobs.len(), 8);
+        remove(&path);
+    }
+}
@@ -0,0 +1,468 @@
+use clap::ValueEnum;
+use cron::Schedule;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Flavour of cron expression accepted on the command line
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Syntax {
+    /// Vixie for 5 fields, seconds for 6 or 7 fields
+    Auto,
+    /// Classic crontab: minute hour day-of-month month day-of-week, with Sunday as 0 or 7
+    Vixie,
+    /// Alias for seconds, without Quartz's L, W and # extensions
+    Quartz,
+    /// Native: second minute hour day-of-month month day-of-week [year], with Sunday as 1
+    Seconds,
+}
+
+/// A cron expression after normalization
+#[derive(Debug, PartialEq, Eq)]
+pub enum Expression {
+    /// Seconds-first expression understood by the cron crate
+    Cron(String),
+    /// Crontab line restricting both day-of-month and day-of-week, which runs
+    /// whenever either of the two seconds-first expressions matches
+    Either(String, String),
+    /// `@reboot`: run once when croncycle starts
+    Reboot,
+}
+
+/// A parsed cron expression
+pub enum Parsed {
+    Cron(Box<Schedule>),
+    /// Runs on the days of the month of the first schedule and the days of the week of the second
+    Either(Box<Schedule>, Box<Schedule>),
+    Reboot,
+}
+
+/// Why a cron expression could not be parsed, pointing at the offending part
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    expr: String,
+    syntax: Syntax,
+    /// Byte range of the offending token in `expr`
+    span: Option<Range<usize>>,
+    message: String,
+    hints: Vec<String>,
+}
+
+/// A field of a seconds-first expression
+struct Field {
+    name: &'static str,
+    min: u32,
+    max: u32,
+    names: &'static [&'static str],
+    any: bool,
+}
+
+const DAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+const MONTH_NAMES: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+const FIELDS: [Field; 7] = [
+    Field { name: "second", min: 0, max: 59, names: &[], any: false },
+    Field { name: "minute", min: 0, max: 59, names: &[], any: false },
+    Field { name: "hour", min: 0, max: 23, names: &[], any: false },
+    Field { name: "day-of-month", min: 1, max: 31, names: &[], any: true },
+    Field { name: "month", min: 1, max: 12, names: &MONTH_NAMES, any: false },
+    Field { name: "day-of-week", min: 1, max: 7, names: &DAY_NAMES, any: true },
+    Field { name: "year", min: 1970, max: 2100, names: &[], any: false },
+];
+
+/// Parses an expression in the given syntax, or one of the `@` macros.
+pub fn parse(expr: &str, syntax: Syntax) -> Result<Parsed, ParseError> {
+    let schedule = |cron: &str| Schedule::from_str(cron).map(Box::new).map_err(|_| diagnose(expr, resolve(syntax, expr)));
+    match normalize(expr, syntax)? {
+        Expression::Reboot => Ok(Parsed::Reboot),
+        Expression::Cron(cron) => Ok(Parsed::Cron(schedule(&cron)?)),
+        Expression::Either(by_month_day, by_week_day) => Ok(Parsed::Either(schedule(&by_month_day)?, schedule(&by_week_day)?)),
+    }
+}
+
+/// Picks the syntax `Auto` stands for, going by the number of fields.
+pub fn resolve(syntax: Syntax, expr: &str) -> Syntax {
+    match (syntax, expr.split_whitespace().count()) {
+        (Syntax::Auto, 5) => Syntax::Vixie,
+        (Syntax::Auto, _) => Syntax::Seconds,
+        (syntax, _) => syntax,
+    }
+}
+
+/// Rewrites an expression in the given syntax, or one of the `@` macros, into
+/// the seconds-first form the cron crate parses.
+pub fn normalize(expr: &str, syntax: Syntax) -> Result<Expression, ParseError> {
+    let trimmed = expr.trim();
+
+    if trimmed.starts_with('@') {
+        let cron = match trimmed.to_lowercase().as_str() {
+            "@reboot" => return Ok(Expression::Reboot),
+            "@yearly" | "@annually" => "0 0 0 1 1 *",
+            "@monthly" => "0 0 0 1 * *",
+            "@weekly" => "0 0 0 * * Sun",
+            "@daily" | "@midnight" => "0 0 0 * * *",
+            "@hourly" => "0 0 * * * *",
+            _ => {
+                let mut error = ParseError::new(expr, syntax, format!("unknown macro '{}'", trimmed));
+                error.span = tokens(expr).first().map(|(span, _)| span.clone());
+                error.hints.push("Supported macros are @reboot, @yearly, @annually, @monthly, @weekly, @daily, @midnight and @hourly".to_string());
+                return Err(error);
+            },
+        };
+        return Ok(Expression::Cron(cron.to_string()));
+    }
+
+    let fields = tokens(expr);
+
+    match resolve(syntax, expr) {
+        Syntax::Vixie if fields.len() == 5 => {
+            let day_of_week = vixie_day_of_week(fields[4].1).map_err(|(item, message)| {
+                let mut error = ParseError::new(expr, Syntax::Vixie, format!("invalid day-of-week field: {}", message));
+                error.span = Some(item_span(&fields[4], item));
+                error
+            })?;
+            // Crontab runs on days matching either day field when both are restricted,
+            // where the cron crate requires both to match. Like in Vixie cron, a field
+            // starting with `*` does not count as restricted.
+            let restricted = |field: &str| !field.starts_with('*') && field != "?";
+            let both_restricted = restricted(fields[2].1) && restricted(fields[4].1);
+            let fields: Vec<&str> = fields[..4].iter().map(|(_, field)| *field).collect();
+            if both_restricted {
+                let (minute, hour, day_of_month, month) = (fields[0], fields[1], fields[2], fields[3]);
+                return Ok(Expression::Either(
+                    format!("0 {} {} {} {} *", minute, hour, day_of_month, month),
+                    format!("0 {} {} * {} {}", minute, hour, month, day_of_week),
+                ));
+            }
+            Ok(Expression::Cron(format!("0 {} {}", fields.join(" "), day_of_week)))
+        },
+        Syntax::Vixie => Err(field_count_error(expr, syntax, fields.len())),
+        Syntax::Quartz | Syntax::Seconds | Syntax::Auto if !(6..=7).contains(&fields.len()) => {
+            Err(field_count_error(expr, syntax, fields.len()))
+        },
+        Syntax::Quartz | Syntax::Seconds | Syntax::Auto => {
+            let fields: Vec<&str> = fields.iter().map(|(_, field)| *field).collect();
+            Ok(Expression::Cron(fields.join(" ")))
+        },
+    }
+}
+
+/// Splits an expression into whitespace separated tokens with their byte ranges.
+fn tokens(expr: &str) -> Vec<(Range<usize>, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in expr.char_indices().chain(std::iter::once((expr.len(), ' '))) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                tokens.push((s..i, &expr[s..i]));
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    tokens
+}
+
+/// Byte range of `item`, a subslice of the token's text, in the expression.
+fn item_span(token: &(Range<usize>, &str), item: &str) -> Range<usize> {
+    let start = token.0.start + (item.as_ptr() as usize - token.1.as_ptr() as usize);
+    start..start + item.len()
+}
+
+fn field_count_error(expr: &str, syntax: Syntax, count: usize) -> ParseError {
+    let expected = match syntax {
+        Syntax::Auto => "5 (crontab) or 6 to 7 (seconds-first)",
+        Syntax::Vixie => "5",
+        Syntax::Quartz | Syntax::Seconds => "6 or 7",
+    };
+    let mut error = ParseError::new(expr, syntax, format!("expected {} fields, found {}", expected, count));
+    match (syntax, count) {
+        (Syntax::Vixie, 6 | 7) => {
+            error.hints.push("This looks like a seconds-first expression, drop --syntax vixie to use it as is".to_string());
+        },
+        (Syntax::Quartz | Syntax::Seconds, 5) => {
+            error.hints.push(format!(
+                "This looks like a crontab line without a seconds field, add one ('0 {}') or use --syntax vixie",
+                expr.trim(),
+            ));
+        },
+        _ => {},
+    }
+    error
+}
+
+/// Finds out which field of an expression the cron crate rejected and why.
+fn diagnose(expr: &str, syntax: Syntax) -> ParseError {
+    let tokens = tokens(expr);
+    // Crontab lines lack the seconds field, which normalization fills in.
+    let offset = if syntax == Syntax::Vixie { 1 } else { 0 };
+
+    for (i, token) in tokens.iter().enumerate() {
+        let field = &FIELDS[i + offset];
+        // Normalization has already checked the crontab day-of-week field.
+        if (offset == 1 && field.name == "day-of-week") || check_field(i + offset, token.1) {
+            continue;
+        }
+
+        let (item, message) = explain_field(field, token.1);
+        let mut error = ParseError::new(expr, syntax, format!("invalid {} field: {}", field.name, message));
+        error.span = Some(item_span(token, item));
+
+        if field.name == "day-of-week" && item.split(['-', '/']).any(|value| value == "0") {
+            error.hints.push(
+                "Sunday is 1 and Saturday is 7 in seconds-first syntax, 0 for Sunday is crontab numbering; use day names like Sun-Sat or --syntax vixie".to_string(),
+            );
+        }
+        if field.name == "day-of-month" && item.split(['-', '/']).any(|value| value == "0") {
+            error.hints.push("Days of the month start at 1".to_string());
+        }
+        if let Some(neighbour) = other_field_for(i + offset, token.1, offset..tokens.len() + offset).filter(|_| error.hints.is_empty()) {
+            error.hints.push(format!("'{}' would be a valid {} field, are the fields in the right order?", token.1, neighbour));
+        }
+        return error;
+    }
+
+    ParseError::new(expr, syntax, "the cron crate rejected the expression".to_string())
+}
+
+/// Whether the cron crate accepts `field` in position `index`, all other fields being valid.
+fn check_field(index: usize, field: &str) -> bool {
+    let mut fields = ["0", "0", "0", "1", "1", "*", "*"];
+    fields[index] = field;
+    Schedule::from_str(&fields.join(" ")).is_ok()
+}
+
+/// Names another field adjacent to `index` and within `fields` that would accept `field`.
+fn other_field_for(index: usize, field: &str, fields: Range<usize>) -> Option<&'static str> {
+    [index.checked_sub(1), Some(index + 1)]
+        .into_iter()
+        .flatten()
+        .filter(|other| fields.contains(other) && FIELDS[*other].name != "year")
+        .find(|other| check_field(*other, field))
+        .map(|other| FIELDS[other].name)
+}
+
+/// Points out the offending item in a field rejected by the cron crate.
+fn explain_field<'a>(field: &Field, text: &'a str) -> (&'a str, String) {
+    for item in text.split(',') {
+        let (base, step) = match item.split_once('/') {
+            Some((base, step)) => (base, Some(step)),
+            None => (item, None),
+        };
+        if let Some(step) = step {
+            if !matches!(step.parse::<u32>(), Ok(step) if step > 0) {
+                return (item, format!("'{}' is not a valid step, expected a positive number", step));
+            }
+        }
+        if base == "*" {
+            continue;
+        }
+        if base == "?" {
+            if field.any {
+                continue;
+            }
+            return (item, "'?' is only allowed in the day-of-month and day-of-week fields".to_string());
+        }
+
+        let values: Vec<&str> = base.splitn(2, '-').collect();
+        let mut parsed = Vec::new();
+        for value in &values {
+            match field_value(field, value) {
+                Ok(value) => parsed.push(value),
+                Err(message) => return (item, message),
+            }
+        }
+        if let [start, end] = parsed[..] {
+            if start > end {
+                return (item, format!("range {} goes backwards", base));
+            }
+        }
+    }
+
+    (text, format!("'{}' is not valid here", text))
+}
+
+fn field_value(field: &Field, value: &str) -> Result<u32, String> {
+    if let Ok(number) = value.parse::<u32>() {
+        if number < field.min || number > field.max {
+            return Err(format!("{} is out of range {}-{}", number, field.min, field.max));
+        }
+        return Ok(number);
+    }
+    if value.is_empty() {
+        return Err("missing value".to_string());
+    }
+    if field.names.is_empty() {
+        return Err(format!("'{}' is not a number", value));
+    }
+    match field.names.iter().position(|name| name.eq_ignore_ascii_case(value.get(..3).unwrap_or(value))) {
+        Some(index) => Ok(index as u32 + field.min),
+        None => Err(format!("'{}' is not a valid {} name, expected one of {}", value, field.name, field.names.join(", "))),
+    }
+}
+
+impl ParseError {
+    fn new(expr: &str, syntax: Syntax, message: String) -> Self {
+        ParseError {
+            expr: expr.to_string(),
+            syntax,
+            span: None,
+            message,
+            hints: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid cron expression: {}", self.message)?;
+        writeln!(f, "    {}", self.expr)?;
+        if let Some(span) = &self.span {
+            let indent = self.expr[..span.start].chars().count();
+            let width = self.expr[span.clone()].chars().count().max(1);
+            writeln!(f, "    {}{}", " ".repeat(indent), "^".repeat(width))?;
+        }
+        let crontab = "minute hour day-of-month month day-of-week";
+        let seconds = "second minute hour day-of-month month day-of-week [year]";
+        match self.syntax {
+            Syntax::Auto => write!(f, "Fields are: {} (crontab) or {} (seconds-first)", crontab, seconds)?,
+            Syntax::Vixie => write!(f, "Fields are: {}", crontab)?,
+            Syntax::Quartz | Syntax::Seconds => write!(f, "Fields are: {}", seconds)?,
+        }
+        for hint in &self.hints {
+            write!(f, "\nHint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Translates a day-of-week field counting Sunday as 0 or 7 into day names,
+/// which mean the same in every syntax. Errors name the offending item.
+fn vixie_day_of_week(field: &str) -> Result<String, (&str, String)> {
+    if field == "*" || field == "?" {
+        return Ok(field.to_string());
+    }
+
+    let mut days = [false; 7];
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step = step.parse::<usize>().ok().filter(|step| *step > 0);
+                (range, step.ok_or_else(|| (item, format!("invalid step in '{}'", item)))?)
+            },
+            None => (item, 1),
+        };
+        let day = |day| vixie_day(day).map_err(|message| (item, message));
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((start, end)) => (day(start)?, day(end)?),
+            None if step > 1 => (day(range)?, 6),
+            None => (day(range)?, day(range)?),
+        };
+        if start > end {
+            return Err((item, format!("range {} goes backwards", range)));
+        }
+        // 7 is Sunday as well, so "5-7" means Friday to Sunday.
+        for day in (start..=end).step_by(step) {
+            days[day % 7] = true;
+        }
+    }
+
+    if days.iter().all(|day| *day) {
+        return Ok("*".to_string());
+    }
+    Ok(DAY_NAMES
+        .iter()
+        .zip(days)
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+fn vixie_day(day: &str) -> Result<usize, String> {
+    if let Ok(day) = day.parse::<usize>() {
+        return match day {
+            0..=7 => Ok(day),
+            _ => Err(format!("{} is out of range 0-7", day)),
+        };
+    }
+    DAY_NAMES
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(day))
+        .ok_or_else(|| format!("'{}' is not a valid day-of-week name, expected one of {}", day, DAY_NAMES.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cron(expr: &str, syntax: Syntax) -> String {
+        match normalize(expr, syntax).unwrap() {
+            Expression::Cron(cron) => cron,
+            expression => panic!("unexpected {:?}", expression),
+        }
+    }
+
+    #[test]
+    fn detects_vixie() {
+        assert_eq!(cron("*/5 * * * *", Syntax::Auto), "0 */5 * * * *");
+        assert_eq!(cron("0 0 9 * * Mon-Fri", Syntax::Auto), "0 0 9 * * Mon-Fri");
+    }
+
+    #[test]
+    fn translates_vixie_day_of_week() {
+        assert_eq!(cron("30 9 * * 1-5", Syntax::Vixie), "0 30 9 * * Mon,Tue,Wed,Thu,Fri");
+        assert_eq!(cron("0 0 * * 5-7", Syntax::Vixie), "0 0 0 * * Sun,Fri,Sat");
+        assert_eq!(cron("0 0 * * 0,7", Syntax::Vixie), "0 0 0 * * Sun");
+        assert_eq!(cron("0 0 * * 0-7", Syntax::Vixie), "0 0 0 * * *");
+        assert_eq!(cron("0 0 * * */2", Syntax::Vixie), "0 0 0 * * Sun,Tue,Thu,Sat");
+        assert!(normalize("0 0 * * 8", Syntax::Vixie).is_err());
+    }
+
+    #[test]
+    fn vixie_day_fields_match_either() {
+        assert_eq!(
+            normalize("0 0 1 * 1", Syntax::Vixie),
+            Ok(Expression::Either("0 0 0 1 * *".to_string(), "0 0 0 * * Mon".to_string())),
+        );
+        assert_eq!(
+            normalize("30 9 1,15 Jan-Jun Sat,Sun", Syntax::Auto),
+            Ok(Expression::Either("0 30 9 1,15 Jan-Jun *".to_string(), "0 30 9 * Jan-Jun Sun,Sat".to_string())),
+        );
+        // A day field starting with `*` is no restriction, so both have to match.
+        assert_eq!(cron("0 0 */2 * 1", Syntax::Vixie), "0 0 0 */2 * Mon");
+        assert_eq!(cron("0 0 1 * *", Syntax::Vixie), "0 0 0 1 * *");
+        assert_eq!(cron("0 0 0 1 * Mon", Syntax::Seconds), "0 0 0 1 * Mon");
+    }
+
+    #[test]
+    fn expands_macros() {
+        assert_eq!(cron("@daily", Syntax::Auto), "0 0 0 * * *");
+        assert_eq!(cron("@annually", Syntax::Seconds), "0 0 0 1 1 *");
+        assert_eq!(normalize("@reboot", Syntax::Auto), Ok(Expression::Reboot));
+        assert!(normalize("@sometimes", Syntax::Auto).is_err());
+    }
+
+    #[test]
+    fn points_at_offending_token() {
+        let error = parse("0 30 9 * * Mon-Fry", Syntax::Auto).err().unwrap();
+        assert_eq!(error.span, Some(11..18));
+        assert_eq!(error.message, "invalid day-of-week field: 'Fry' is not a valid day-of-week name, expected one of Sun, Mon, Tue, Wed, Thu, Fri, Sat");
+
+        let error = parse("0 0 25 * * *", Syntax::Auto).err().unwrap();
+        assert_eq!(error.span, Some(4..6));
+        assert_eq!(error.message, "invalid hour field: 25 is out of range 0-23");
+    }
+
+    #[test]
+    fn suggests_fixes() {
+        let error = parse("0 0 9 * * 0", Syntax::Auto).err().unwrap();
+        assert!(error.hints[0].starts_with("Sunday is 1"));
+
+        let error = parse("*/5 * * * *", Syntax::Seconds).err().unwrap();
+        assert!(error.hints[0].contains("'0 */5 * * * *'"));
+    }
+}